@@ -0,0 +1,37 @@
+//! markdownをepub 3.0.1(または2.0.1)形式に変換する
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use repub::RepubBuilder;
+//!
+//! let epub = RepubBuilder::from_path(Path::new("markdown_directory"))
+//!     .titled("タイトル")
+//!     .creator("作者")
+//!     .language("ja")
+//!     .build()
+//!     .unwrap();
+//! println!("{:?}", epub);
+//! ```
+//!
+//! ファイルを介さず、メモリ上のmarkdownから章を組み立てることもできる
+//!
+//! ```no_run
+//! use repub::RepubBuilder;
+//!
+//! let epub = RepubBuilder::default()
+//!     .add_chapter("01_intro", "# はじめに")
+//!     .add_chapter("02_body", "# 本文")
+//!     .titled("タイトル")
+//!     .language("ja")
+//!     .build()
+//!     .unwrap();
+//! ```
+
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate log;
+
+mod repub;
+
+pub use crate::repub::{ChapterNumbering, Creator, Direction, EpubVersion, RepubBuilder, TextStats, TocPosition, TocSource, Viewport, WritingMode};
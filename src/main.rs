@@ -1,11 +1,19 @@
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
-mod repub;
+use chrono::{DateTime, TimeZone, Utc};
+use clap::ArgMatches;
+use failure::ResultExt;
+use log::{Level, LevelFilter};
+use repub::{ChapterNumbering, RepubBuilder};
+use serde::Deserialize;
 
 #[macro_use]
 extern crate clap;
 #[macro_use]
 extern crate failure;
+#[macro_use]
+extern crate log;
 
 fn main() {
     use clap::{App, Arg};
@@ -14,11 +22,17 @@ fn main() {
         .author(crate_authors!())
         .about(crate_description!())
         // .mdファイルorフォルダ
-        .arg(Arg::from_usage("<input> '変換するマークダウンファイル OR 変換するマークダウン文書(複数可)の入ったディレクトリ'"))
+        .arg(Arg::from_usage("<input> '変換するマークダウンファイル OR 変換するマークダウン文書(複数可)の入ったディレクトリ OR 標準入力から読む\"-\"'"))
+        // 設定ファイル
+        .arg(Arg::with_name("config")
+            .help("設定ファイル(省略時は入力ディレクトリのrepub.toml) コマンドラインの指定が優先")
+            .long("config")
+            .takes_value(true))
         // 一時ファイルを消さない
         .arg(Arg::with_name("save_tmp_files")
-            .help("一時ファイルを消去せずそのままにする")
-            .long("save"))
+            .help("一時ファイルを消去せずそのままにする(作業ディレクトリを表示する)")
+            .long("save")
+            .visible_aliases(&["keep-temp", "save-tmp"]))
         // タイトル
         .arg(Arg::with_name("title")
             .help("タイトルを設定")
@@ -27,54 +41,805 @@ fn main() {
             .takes_value(true))
         // 著者
         .arg(Arg::with_name("creator")
-            .help("作者、編集者、翻訳者など")
+            .help("作者、編集者、翻訳者など(複数可) 役割は\"名前:trl\"のようにMARC relatorコードで指定")
             .short("c")
             .long("creator")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        // 協力者
+        .arg(Arg::with_name("contributor")
+            .help("挿絵、装丁、校正などの協力者(複数可) 役割は\"名前:ill\"のようにMARC relatorコードで指定(省略時はctb)")
+            .long("contributor")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        // 標準入力から読んだ章の名前
+        .arg(Arg::with_name("name")
+            .help("標準入力から読むときの章のファイル名(省略時はタイトル)")
+            .long("name")
+            .takes_value(true))
+        // 出版社
+        .arg(Arg::with_name("publisher")
+            .help("出版社")
+            .short("p")
+            .long("publisher")
+            .takes_value(true))
+        // シリーズ
+        .arg(Arg::with_name("series")
+            .help("シリーズ名(Calibreのシリーズとbelongs-to-collectionに書き出す)")
+            .long("series")
+            .takes_value(true))
+        .arg(Arg::with_name("series_index")
+            .help("シリーズの中の巻数(\"2\"や\"2.5\")")
+            .long("series-index")
+            .requires("series")
+            .takes_value(true))
+        // 著作権表示
+        .arg(Arg::with_name("rights")
+            .help("著作権表示(\"© 2019 著者, CC BY 4.0\"など)")
+            .long("rights")
+            .takes_value(true))
+        // 紹介文
+        .arg(Arg::with_name("description")
+            .help("紹介文")
+            .long("description")
+            .takes_value(true))
+        // ジャンル,キーワード
+        .arg(Arg::with_name("subject")
+            .help("ジャンルやキーワード(複数可)")
+            .long("subject")
+            .multiple(true)
+            .number_of_values(1)
             .takes_value(true))
         // 言語
         .arg(Arg::with_name("language")
-            .help("言語")
+            .help("言語(複数可 最初のものが主な言語)")
             .short("l")
             .long("language")
-            .takes_value(true))
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("no_validate_language")
+            .help("言語タグがBCP 47の形か確かめない")
+            .long("no-validate-language"))
         // id
         .arg(Arg::with_name("book_id")
-            .help("Book ID")
+            .help("Book ID(\"urn:isbn:...\"や\"urn:uuid:...\"のようにschemeを付けられる)")
             .short("id")
             .long("bookid")
             .takes_value(true))
+        // 同じ本なら同じid
+        .arg(Arg::with_name("deterministic_id")
+            .help("Book IDを指定しないとき、タイトルと作者から毎回同じIDを作る")
+            .long("deterministic-id"))
+        // 再現可能なビルド
+        .arg(Arg::with_name("deterministic")
+            .help("同じ入力から毎回同じ.epubを作る(SOURCE_DATE_EPOCHがあればその日時を使う)")
+            .long("deterministic"))
+        // ISBN
+        .arg(Arg::with_name("isbn")
+            .help("ISBN(10桁または13桁)を識別子にする")
+            .long("isbn")
+            .conflicts_with("book_id")
+            .takes_value(true))
         // 縦書き
         .arg(Arg::with_name("vertical")
             .help("縦書き")
             .short("v")
             .long("vertical"))
+        // 出版日
+        .arg(Arg::with_name("date")
+            .help("出版日(YYYY-MM-DD) 省略時はfront matterのdate")
+            .long("date")
+            .takes_value(true))
+        // ページ送りの方向
+        .arg(Arg::with_name("direction")
+            .help("ページ送りの方向(綴じ方向) 省略時は書字方向に合わせる")
+            .long("direction")
+            .possible_values(&["ltr", "rtl", "default"])
+            .takes_value(true))
+        // 書字方向
+        .arg(Arg::with_name("writing_mode")
+            .help("書字方向 -vはvertical-rlと同じ")
+            .long("writing-mode")
+            .possible_values(&["horizontal-tb", "vertical-rl", "vertical-lr"])
+            .conflicts_with("vertical")
+            .takes_value(true))
+        // 固定レイアウト
+        .arg(Arg::with_name("fixed_layout")
+            .help("固定レイアウト(pre-paginated)にする 漫画など画像だけのページ向け(--viewportで幅x高さの指定が必要)")
+            .long("fixed-layout"))
+        // アクセシビリティ
+        .arg(Arg::with_name("strict_a11y")
+            .help("代替テキスト(alt)のない画像を警告ではなくエラーにする")
+            .long("strict-a11y"))
+        // viewport
+        .arg(Arg::with_name("viewport")
+            .help("各ページにviewportのmeta要素を書く(\"device-width\"または固定レイアウト用の\"幅x高さ\")")
+            .long("viewport")
+            .takes_value(true))
+        // 更新日時
+        .arg(Arg::with_name("modified")
+            .help("dcterms:modifiedに書き込む更新日時(RFC 3339) 再現可能なビルドのために固定する")
+            .long("modified")
+            .env("REPUB_MODIFIED")
+            .takes_value(true))
+        // 改行
+        .arg(Arg::with_name("no_hardbreaks")
+            .help("段落内の改行を<br />に変換しない")
+            .long("no-hardbreaks"))
         // スタイル
         .arg(Arg::with_name("style")
-            .help("cssを指定")
+            .help("cssを指定(複数可 指定した順に読み込む)")
             .short("s")
             .long("css")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        // ページの雛形
+        .arg(Arg::with_name("template")
+            .help("各ページのxhtmlの雛形({title}, {style_links}, {body}を置き換える)")
+            .long("template")
+            .takes_value(true))
+        // 表紙
+        .arg(Arg::with_name("cover")
+            .help("表紙画像(png, jpeg)を指定")
+            .long("cover")
+            .takes_value(true))
+        // フォント
+        .arg(Arg::with_name("font")
+            .help("埋め込むフォント(ttf, otf, woff2)を指定(複数可)")
+            .long("font")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        // そのまま入れるxhtml
+        .arg(Arg::with_name("append_chapter")
+            .help("markdownを通さずにそのまま入れるxhtmlのページ(複数可) \"copyright.xhtml@2\"のように読む順の位置(1始まり)を付けられる")
+            .long("append-chapter")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        // 出力先
+        .arg(Arg::with_name("output")
+            .help("出力する.epubファイルのpath(省略時は\"タイトル.epub\")")
+            .short("o")
+            .long("output")
+            .takes_value(true))
+        // 出力先のフォルダ
+        .arg(Arg::with_name("output_dir")
+            .help("\"タイトル.epub\"を書き出すフォルダ(なければ作る)")
+            .long("output-dir")
+            .conflicts_with("output")
+            .takes_value(true))
+        // 作業ディレクトリの場所
+        .arg(Arg::with_name("tmp_dir")
+            .help("作業ディレクトリを作る場所(省略時はOSの一時ディレクトリ)")
+            .long("tmp-dir")
+            .takes_value(true))
+        // media-typeの上書き
+        .arg(Arg::with_name("media_type_override")
+            .help("拡張子のmedia-typeを上書きする(複数可) \"webp=image/webp\"のように指定")
+            .long("media-type-override")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        // 中身のフォルダ
+        .arg(Arg::with_name("content_dir")
+            .help(".epubの中で本の中身を置くフォルダの名前(省略時はOEBPS)")
+            .long("content-dir")
+            .takes_value(true))
+        // markdownの拡張子
+        .arg(Arg::with_name("ext")
+            .help("markdownとして読む拡張子(複数可 省略時はmd, markdown, mdown, mkd)")
+            .long("ext")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        // サブディレクトリの深さ
+        .arg(Arg::with_name("max_depth")
+            .help("読み込むサブディレクトリの深さ(0でサブディレクトリを読まない)")
+            .long("max-depth")
+            .takes_value(true))
+        // order.txtに載っていないファイル
+        .arg(Arg::with_name("omit_unlisted")
+            .help("order.txtに載っていないファイルを変換しない")
+            .long("omit-unlisted"))
+        // EPUBのバージョン
+        .arg(Arg::with_name("epub_version")
+            .help("出力するEPUBのバージョン(省略時は3 2ではページをXHTML 1.1で書き出す)")
+            .long("epub-version")
+            .possible_values(&["2", "3"])
+            .takes_value(true))
+        // 約物
+        .arg(Arg::with_name("smart")
+            .help("引用符やダッシュを約物に置き換える(\"...\"->“...”, --->—)")
+            .long("smart"))
+        // 変換できないファイルを飛ばす
+        .arg(Arg::with_name("continue_on_error")
+            .help("変換できないファイルがあっても止めず、飛ばして残りで.epubを作る(飛ばしたファイルは最後に表示する)")
+            .long("continue-on-error"))
+        // 生のHTML
+        .arg(Arg::with_name("allow_html")
+            .help("markdown中のHTMLをエスケープせずそのまま書き出す(信頼できる文書にだけ使う XMLとして壊れていればエラー)")
+            .long("allow-html"))
+        // comrakの設定
+        .arg(Arg::with_name("comrak_opt")
+            .help("markdownの変換設定をkey=valueで変える(複数可 tables, strikethrough, autolink, footnotes, tasklist, superscript, smart, unsafe, hardbreaks)")
+            .long("comrak-opt")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        // 文字コード
+        .arg(Arg::with_name("encoding")
+            .help("mdファイルの文字コード(\"shift_jis\", \"euc-jp\", \"latin1\"など 省略時は推測する)")
+            .long("encoding")
+            .takes_value(true))
+        // 章を一つのファイルにまとめる
+        .arg(Arg::with_name("merge")
+            .help("すべての章を一つのxhtml(content.xhtml)にまとめる(短い文書向け)")
+            .long("merge")
+            .conflicts_with("split_level"))
+        // 見出しでファイルを分ける
+        .arg(Arg::with_name("split_level")
+            .help("mdファイルをこのレベルの見出し(1~6)ごとに別のページに分ける")
+            .long("split-level")
+            .takes_value(true))
+        // 書き出さずに構成を見る
+        .arg(Arg::with_name("dry_run")
+            .help(".epubを作らず、読む順・manifest・目次を表示する")
+            .long("dry-run"))
+        // 数式
+        .arg(Arg::with_name("math")
+            .help("$...$と$$...$$のLaTeXをMathMLに変換する")
+            .long("math"))
+        // 進み具合を出さない
+        .arg(Arg::with_name("quiet")
+            .help("エラー以外を表示しない(変換の進み具合も出さない)")
+            .short("q")
+            .long("quiet"))
+        // 詳しく出す -vは縦書き
+        .arg(Arg::with_name("verbose")
+            .help("変換中のファイルなどを詳しく表示する")
+            .long("verbose")
+            .conflicts_with("quiet"))
+        // epubcheck
+        .arg(Arg::with_name("check")
+            .help("生成した.epubをepubcheckで検査する(epubcheckが必要)")
+            .long("check"))
+        // 語数・文字数
+        .arg(Arg::with_name("stats")
+            .help("変換後に本文の語数・文字数を表示する(かなや漢字は1文字を1語と数える)")
+            .long("stats"))
+        // 扉
+        .arg(Arg::with_name("title_page")
+            .help("タイトル・作者・出版日から扉(title.xhtml)を作り、表紙の次に置く")
+            .long("title-page"))
+        // landmarks
+        .arg(Arg::with_name("landmarks")
+            .help("目次のページに表紙・目次・本文の始まりを示すlandmarksを加える")
+            .long("landmarks"))
+        // spineでの目次の位置
+        .arg(Arg::with_name("toc_in_spine")
+            .help("目次のページをページ送りのどこに置くか(省略時はfirst) noneでも目次はリーダーから使える")
+            .long("toc-in-spine")
+            .possible_values(&["first", "last", "none"])
+            .takes_value(true))
+        .arg(Arg::with_name("no_toc_first")
+            .help("目次のページを最初ではなく本文の後ろに置く(--toc-in-spine lastと同じ)")
+            .long("no-toc-first")
+            .conflicts_with("toc_in_spine"))
+        .arg(Arg::with_name("flatten_single_chapter")
+            .help("1章だけの本では目次のページをページ送りに入れない")
+            .long("flatten-single-chapter"))
+        // 目次の見出し
+        .arg(Arg::with_name("toc_title")
+            .help("目次の見出し(省略時は言語に合わせる)")
+            .long("toc-title")
+            .takes_value(true))
+        // コードブロックの色付け
+        .arg(Arg::with_name("highlight_theme")
+            .help("コードブロックの色付けに使うテーマ(省略時はInspiredGitHub)")
+            .long("highlight-theme")
             .takes_value(true))
         // tocに乗せるヘッダーのレベル
         .arg(Arg::with_name("toc_level")
-            .help("目次に表示するHeaderの最低レベル(1~5)")
+            .help("目次に表示するHeaderの最低レベル(1~6)")
             .short("h")
             .takes_value(true))
+        // 目次に載せるヘッダーの最大レベル
+        .arg(Arg::with_name("toc_depth")
+            .help("目次に載せるHeaderの最大レベル(1~6) これより深いHeaderは目次から除く")
+            .long("toc-depth")
+            .takes_value(true))
+        // 目次の項目を何から作るか
+        .arg(Arg::with_name("toc_from")
+            .help("目次を見出しから作るか、章のファイルごとに1項目で作るか(省略時はheadings)")
+            .long("toc-from")
+            .possible_values(&["headings", "files"])
+            .takes_value(true))
+        // 章番号
+        .arg(Arg::with_name("number_chapters")
+            .help("目次の章に番号を付ける(\"1. はじめに\" 章の中の見出しは\"1.1\")")
+            .long("number-chapters"))
+        .arg(Arg::with_name("chapter_numbering")
+            .help("目次の章番号の書き方(省略時はdecimal 指定すると--number-chaptersを含む)")
+            .long("chapter-numbering")
+            .possible_values(&["decimal", "roman", "cjk"])
+            .takes_value(true))
         ;
 
     let matches = app.get_matches();
 
-    match repub::RepubBuilder::new(
-        Path::new(&matches.value_of("input").unwrap()), &matches) {
+    init_logger(&matches);
+
+    match builder_from_matches(&matches) {
         Ok(mut repub_builder) => {
-            match repub_builder.build() {
-                Err(e) => {
-                    eprintln!("{:?}", e);
+            let res = repub_builder.build();
+            if let Some(dir) = repub_builder.saved_tmp_dir() {
+                info!("一時ファイル: {}", dir.display());
+            }
+            // --quietのときはinfo!が出ないので表示されない
+            // --dry-runで組み立てた中身
+            if let Some(plan) = repub_builder.plan() {
+                print!("{}", plan);
+            }
+            if let Some(stats) = repub_builder.text_stats() {
+                info!("語数: {} 文字数: {}", stats.words, stats.characters);
+            }
+            // 作れなかったときも、--checkで問題が見つかったときも失敗として終える
+            if let Err(e) = res {
+                error!("{:?}", e);
+                std::process::exit(1);
+            }
+            // --continue-on-errorで飛ばした章があれば、本はできても失敗として終える
+            let failed = repub_builder.failed_files();
+            if !failed.is_empty() {
+                error!("{}個のファイルを変換できず、飛ばしました", failed.len());
+                for (path, message) in failed {
+                    error!("    {}: {}", path.display(), message);
                 }
-                _ => {}
-            };
+                std::process::exit(1);
+            }
         }
         Err(e) => {
-            eprintln!("{:?}", e);
+            error!("{:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// -q, --verboseに合わせてログの出力先と量を決める
+/// 入力を求めるプロンプトはログではないので、いつでも端末に出る
+fn init_logger(matches: &ArgMatches) {
+    let level = if matches.is_present("quiet") {
+        LevelFilter::Error
+    } else if matches.is_present("verbose") {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+
+    // 依存しているクレートのログは出さない
+    env_logger::Builder::new()
+        .filter(Some("repub"), level)
+        .format(|buf, record| match record.level() {
+            Level::Warn => writeln!(buf, "Warning {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+}
+
+/// コマンドライン引数からRepubBuilderを得る
+fn builder_from_matches(matches: &ArgMatches) -> Result<RepubBuilder, failure::Error> {
+    // コマンドの実行path
+    let origin = &std::env::current_dir()?;
+
+    let input = matches.value_of("input").unwrap();
+    let mut repub_builder = if input == "-" {
+        // 標準入力 一つの章として変換する
+        let name = matches.value_of("name")
+            .or_else(|| matches.value_of("title"))
+            .ok_or_else(|| format_err!("[ERROR] --title or --name is required to read markdown from stdin."))?;
+        let mut markdown = String::new();
+        std::io::stdin().read_to_string(&mut markdown)
+            .context("Failed to read stdin.")?;
+        RepubBuilder::from_markdown(name, &markdown)?
+    } else {
+        RepubBuilder::from_path(&origin.join(Path::new(input)))
+    };
+
+    // 設定ファイル コマンドライン引数で上書きする
+    let config_path = match matches.value_of("config") {
+        Some(config) => Some(origin.join(config)),
+        None if input == "-" => None,
+        None => {
+            let input = origin.join(input);
+            let dir = if input.is_dir() { Some(input.as_path()) } else { input.parent() };
+            dir.map(|dir| dir.join("repub.toml")).filter(|path| path.is_file())
+        }
+    };
+    if let Some(config_path) = config_path {
+        Config::load(&config_path)?.apply(&mut repub_builder, &config_path, matches);
+    }
+
+    if matches.is_present("vertical") {
+        repub_builder.vertical(true);
+    }
+    repub_builder
+        .save_tmp_files(matches.is_present("save_tmp_files"))
+        .omit_unlisted(matches.is_present("omit_unlisted"))
+        .deterministic_id(matches.is_present("deterministic_id"))
+        .deterministic(matches.is_present("deterministic"))
+        .landmarks(matches.is_present("landmarks"))
+        .title_page(matches.is_present("title_page"))
+        .stats(matches.is_present("stats"))
+        .toc_first(!matches.is_present("no_toc_first"))
+        .flatten_single_chapter(matches.is_present("flatten_single_chapter"))
+        .check(matches.is_present("check"))
+        .quiet(matches.is_present("quiet"))
+        .math(matches.is_present("math"))
+        .smart(matches.is_present("smart"))
+        .allow_html(matches.is_present("allow_html"))
+        .merge(matches.is_present("merge"))
+        .continue_on_error(matches.is_present("continue_on_error"))
+        .fixed_layout(matches.is_present("fixed_layout"))
+        .strict_a11y(matches.is_present("strict_a11y"))
+        .validate_language(!matches.is_present("no_validate_language"))
+        .dry_run(matches.is_present("dry_run"));
+
+    // タイトル
+    if let Some(title) = matches.value_of("title") {
+        repub_builder.titled(title);
+    }
+
+    // 作者,編集者,著者
+    if let Some(creators) = matches.values_of("creator") {
+        for creator in creators {
+            repub_builder.creator(creator);
+        }
+    }
+    if let Some(contributors) = matches.values_of("contributor") {
+        for contributor in contributors {
+            repub_builder.contributor(contributor);
+        }
+    }
+
+    // 言語
+    if let Some(mut languages) = matches.values_of("language") {
+        if let Some(language) = languages.next() {
+            repub_builder.language(language);
+        }
+        for language in languages {
+            repub_builder.other_language(language);
+        }
+    }
+
+    // 出版社
+    if let Some(publisher) = matches.value_of("publisher") {
+        repub_builder.publisher(publisher);
+    }
+
+    // シリーズ
+    if let Some(series) = matches.value_of("series") {
+        repub_builder.series(series);
+    }
+    if let Some(index) = matches.value_of("series_index") {
+        repub_builder.series_index(index)?;
+    }
+
+    // 著作権表示
+    if let Some(rights) = matches.value_of("rights") {
+        repub_builder.rights(rights);
+    }
+
+    // 紹介文
+    if let Some(description) = matches.value_of("description") {
+        repub_builder.description(description);
+    }
+
+    // ジャンル,キーワード
+    if let Some(subjects) = matches.values_of("subject") {
+        for subject in subjects {
+            repub_builder.subject(subject);
+        }
+    }
+
+    // 出版日
+    if let Some(date) = matches.value_of("date") {
+        repub_builder.date(date)?;
+    }
+
+    // 更新日時
+    if let Some(modified) = matches.value_of("modified") {
+        let modified = DateTime::parse_from_rfc3339(modified)
+            .map_err(|_| format_err!("[ERROR] {} is not RFC 3339 date time.", modified))?;
+        repub_builder.modified(modified.with_timezone(&Utc));
+    } else if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        // reproducible-builds.orgの取り決め
+        let epoch = epoch.trim().parse::<i64>()
+            .map_err(|_| format_err!("[ERROR] SOURCE_DATE_EPOCH {} is not UNIX time.", epoch))?;
+        // 範囲外の値でpanicしないようにする
+        let modified = Utc.timestamp_opt(epoch, 0).single()
+            .ok_or_else(|| format_err!("[ERROR] SOURCE_DATE_EPOCH {} is out of range.", epoch))?;
+        repub_builder
+            .modified(modified)
+            .deterministic(true);
+    }
+
+    if let Some(id) = matches.value_of("book_id") {
+        info!("Book ID: {}", id);
+        repub_builder.book_id(id)?;
+    }
+
+    // ISBN
+    if let Some(isbn) = matches.value_of("isbn") {
+        repub_builder.isbn(isbn)?;
+    }
+
+    // css style
+    if let Some(styles) = matches.values_of("style") {
+        for css in styles {
+            repub_builder.style(origin.join(css));
+        }
+    }
+
+    // ページの雛形
+    if let Some(template) = matches.value_of("template") {
+        repub_builder.template(origin.join(template));
+    }
+
+    // 表紙
+    if let Some(cover) = matches.value_of("cover") {
+        repub_builder.cover(origin.join(cover));
+    }
+
+    // フォント
+    if let Some(fonts) = matches.values_of("font") {
+        for font in fonts {
+            repub_builder.font(origin.join(font));
+        }
+    }
+
+    // そのまま入れるxhtml "path@位置"
+    if let Some(chapters) = matches.values_of("append_chapter") {
+        for chapter in chapters {
+            let (path, position) = match chapter.rfind('@') {
+                Some(at) => match chapter[at + 1..].parse::<usize>() {
+                    Ok(position) if position >= 1 => (&chapter[..at], Some(position)),
+                    _ => (chapter, None),
+                },
+                None => (chapter, None),
+            };
+            repub_builder.append_chapter(origin.join(path), position);
+        }
+    }
+
+    // 出力先
+    if let Some(output) = matches.value_of("output") {
+        repub_builder.output(origin.join(output));
+    }
+    if let Some(output_dir) = matches.value_of("output_dir") {
+        repub_builder.output_dir(origin.join(output_dir));
+    }
+    if let Some(tmp_dir) = matches.value_of("tmp_dir") {
+        repub_builder.tmp_dir(origin.join(tmp_dir));
+    }
+
+    if let Some(overrides) = matches.values_of("media_type_override") {
+        for media_type in overrides {
+            repub_builder.media_type_override(media_type)?;
+        }
+    }
+
+    if let Some(content_dir) = matches.value_of("content_dir") {
+        repub_builder.content_dir(content_dir);
+    }
+
+    // toc_level
+    if let Some(level) = matches.value_of("toc_level") {
+        match level.parse::<u8>() {
+            Ok(level) => { repub_builder.toc_level(level); }
+            Err(_) => {
+                warn!("{} は目次のレベルに設定できません", &level);
+            }
+        };
+    }
+
+    // toc_depth
+    if let Some(depth) = matches.value_of("toc_depth") {
+        match depth.parse::<u8>() {
+            Ok(depth @ 1..=6) => { repub_builder.toc_depth(depth); }
+            _ => {
+                warn!("{} は目次の深さに設定できません", &depth);
+            }
+        };
+    }
+
+    // 目次の項目
+    if let Some(toc_from) = matches.value_of("toc_from") {
+        repub_builder.toc_from(toc_from.parse()?);
+    }
+
+    // 書字方向
+    if let Some(writing_mode) = matches.value_of("writing_mode") {
+        repub_builder.writing_mode(writing_mode.parse()?);
+    }
+
+    // 章番号
+    if let Some(style) = matches.value_of("chapter_numbering") {
+        repub_builder.number_chapters(style.parse()?);
+    } else if matches.is_present("number_chapters") {
+        repub_builder.number_chapters(ChapterNumbering::Decimal);
+    }
+
+    // spineでの目次の位置
+    if let Some(position) = matches.value_of("toc_in_spine") {
+        repub_builder.toc_in_spine(position.parse()?);
+    }
+
+    // viewport
+    if let Some(viewport) = matches.value_of("viewport") {
+        repub_builder.viewport(viewport.parse()?);
+    }
+
+    // ページ送りの方向
+    if let Some(direction) = matches.value_of("direction") {
+        repub_builder.direction(direction.parse()?);
+    }
+
+    // 見出しでファイルを分ける
+    if let Some(level) = matches.value_of("split_level") {
+        match level.parse::<u8>() {
+            Ok(level @ 1..=6) => { repub_builder.split_level(level); }
+            _ => {
+                warn!("{} は分ける見出しのレベルに設定できません", &level);
+            }
+        };
+    }
+
+    // EPUBのバージョン
+    if let Some(version) = matches.value_of("epub_version") {
+        repub_builder.epub_version(version.parse()?);
+    }
+
+    // 目次の見出し
+    if let Some(toc_title) = matches.value_of("toc_title") {
+        repub_builder.toc_title(toc_title);
+    }
+
+    // コードブロックの色付け
+    if let Some(theme) = matches.value_of("highlight_theme") {
+        repub_builder.highlight_theme(theme);
+    }
+
+    // 改行
+    if matches.is_present("no_hardbreaks") {
+        repub_builder.hardbreaks(false);
+    }
+
+    // comrakの設定 --smartなどより後に設定する
+    if let Some(options) = matches.values_of("comrak_opt") {
+        for option in options {
+            repub_builder.comrak_option(option)?;
         }
     }
+
+    // 文字コード
+    if let Some(encoding) = matches.value_of("encoding") {
+        repub_builder.encoding(encoding)?;
+    }
+
+    // markdownの拡張子
+    if let Some(extensions) = matches.values_of("ext") {
+        repub_builder.extensions(&extensions.collect::<Vec<_>>());
+    }
+
+    // サブディレクトリの深さ
+    if let Some(depth) = matches.value_of("max_depth") {
+        match depth.parse::<usize>() {
+            Ok(depth) => { repub_builder.max_depth(depth); }
+            Err(_) => {
+                warn!("{} はディレクトリの深さに設定できません", &depth);
+            }
+        };
+    }
+
+    // 指定されなかった項目はfront matterから埋め、それでもなければ尋ねる
+    repub_builder.read_front_matter()?;
+    if interactive() {
+        if repub_builder.require_title().is_err() {
+            repub_builder.titled(&prompt("Title")?);
+        }
+        if !repub_builder.has_creator() {
+            repub_builder.creator(&prompt("Creator")?);
+        }
+        if repub_builder.require_language().is_err() {
+            repub_builder.language(&prompt("Language")?);
+        }
+    }
+
+    Ok(repub_builder)
+}
+
+/// repub.tomlの中身 キーはコマンドライン引数と同じ
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    title: Option<String>,
+    creator: Option<String>,
+    creators: Vec<String>,
+    language: Option<String>,
+    css: Vec<String>,
+    vertical: bool,
+    toc_level: Option<u8>,
+    cover: Option<String>,
+    output: Option<String>,
+    output_dir: Option<String>,
+}
+
+impl Config {
+    fn load(path: &Path) -> Result<Config, failure::Error> {
+        let toml = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("[ERROR] failed to read {:?} : {}", path, e))?;
+        toml::from_str(&toml)
+            .map_err(|e| format_err!("[ERROR] {:?} is malformed : {}", path, e))
+    }
+
+    /// コマンドライン引数で指定されていない項目をRepubBuilderに設定する
+    /// pathは設定ファイルのあるディレクトリからの相対path
+    fn apply(self, repub_builder: &mut RepubBuilder, config_path: &Path, matches: &ArgMatches) {
+        let dir = config_path.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+
+        if let Some(title) = &self.title {
+            repub_builder.titled(title);
+        }
+        if !matches.is_present("creator") {
+            for creator in self.creator.iter().chain(self.creators.iter()) {
+                repub_builder.creator(creator);
+            }
+        }
+        if let Some(language) = &self.language {
+            repub_builder.language(language);
+        }
+        if !matches.is_present("style") {
+            for css in &self.css {
+                repub_builder.style(dir.join(css));
+            }
+        }
+        if self.vertical {
+            repub_builder.vertical(true);
+        }
+        if let Some(level) = self.toc_level {
+            repub_builder.toc_level(level);
+        }
+        if let Some(cover) = &self.cover {
+            repub_builder.cover(dir.join(cover));
+        }
+        // --output-dirを指定したときは設定ファイルのoutputを使わない
+        if let (Some(output), false) = (&self.output, matches.is_present("output_dir")) {
+            repub_builder.output(dir.join(output));
+        }
+        if let Some(output_dir) = &self.output_dir {
+            repub_builder.output_dir(dir.join(output_dir));
+        }
+    }
+}
+
+/// 端末から実行されているか
+/// パイプやCIでは入力を待たず、足りない項目はRepubBuilderのエラーになる
+fn interactive() -> bool {
+    atty::is(atty::Stream::Stdin)
+}
+
+/// 標準入力から1行読む
+fn prompt(label: &str) -> Result<String, failure::Error> {
+    print!("{}: ", label);
+    std::io::stdout().flush().context("Failed to read line.")?;
+
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)
+        .context("Failed to read line.")?;
+    Ok(line.trim().to_string())
 }
@@ -1,15 +1,17 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::io::{Write, Read};
+use std::io::Write;
 
-use rand::Rng;
-use rand::distributions::Alphanumeric;
-use clap::ArgMatches;
-use failure::ResultExt;
+use chrono::{DateTime, Utc};
+use encoding_rs::Encoding;
+use uuid::Uuid;
 
 /// epubに格納予定のファイル
 #[derive(Default, Debug)]
 pub struct TmpFiles {
+    /// 作業ディレクトリ 以下のすべてを含む
+    dir: Option<PathBuf>,
     mimetype: Option<PathBuf>,
     meta_inf: Option<PathBuf>,
     oebps: Option<PathBuf>,
@@ -18,783 +20,4378 @@ pub struct TmpFiles {
 #[derive(Debug)]
 pub struct RepubBuilder {
     source_file: PathBuf,
+    /// ファイルではなくメモリ上にあるmarkdown(標準入力やadd_chapterで渡したもの)と、その仮のpath
+    /// あればsource_fileのファイルは読まず、この順に章にする
+    markdown: Vec<(PathBuf, String)>,
     tmp_files: TmpFiles,
-    style: Option<PathBuf>,
+    /// 本の中身を置くフォルダの名前(OEBPS, EPUBなど)
+    content_dir: String,
+    /// markdownとして読む拡張子
+    extensions: Vec<String>,
+    /// mdファイルの文字コード Noneなら推測する
+    encoding: Option<&'static Encoding>,
+    styles: Vec<PathBuf>,
+    /// そのまま入れるxhtmlのページと、spineでの位置(1始まり)
+    raw_chapters: Vec<(PathBuf, Option<usize>)>,
+    template: Option<PathBuf>,
+    cover: Option<PathBuf>,
+    fonts: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    /// outputを指定しないとき"タイトル.epub"を置くフォルダ
+    output_dir: Option<PathBuf>,
+    /// 作業ディレクトリを作る場所 指定しなければOSの一時ディレクトリ
+    tmp_dir: Option<PathBuf>,
+    /// 拡張子ごとのmedia-typeの上書き
+    media_types: HashMap<String, String>,
     title: String,
-    creator: String,
+    creators: Vec<Creator>,
+    contributors: Vec<Creator>,
     language: String,
-    id: String,
-    vertical: bool,
+    /// 2つめ以降の言語
+    languages: Vec<String>,
+    validate_language: bool,
+    publisher: Option<String>,
+    rights: Option<String>,
+    /// シリーズ名と巻数
+    series: Option<String>,
+    series_index: Option<String>,
+    description: Option<String>,
+    subjects: Vec<String>,
+    modified: Option<DateTime<Utc>>,
+    date: Option<String>,
+    id: Option<String>,
+    deterministic_id: bool,
+    deterministic: bool,
+    writing_mode: WritingMode,
+    direction: Option<Direction>,
+    viewport: Option<Viewport>,
+    fixed_layout: bool,
+    strict_a11y: bool,
+    /// spineでの目次のページの位置
+    toc_in_spine: TocPosition,
+    /// 1章だけの本では目次のページを読む順に入れない
+    flatten_single_chapter: bool,
     toc_level: u8,
+    toc_depth: u8,
+    /// 目次の章番号の書き方 Noneなら番号を付けない
+    chapter_numbering: Option<ChapterNumbering>,
+    toc_from: TocSource,
+    landmarks: bool,
+    /// メタデータから扉(title.xhtml)を作る
+    title_page: bool,
+    check: bool,
+    dry_run: bool,
+    quiet: bool,
+    max_depth: Option<usize>,
+    omit_unlisted: bool,
+    hardbreaks: bool,
+    smart: bool,
+    /// markdown中のHTMLをそのまま書き出す
+    allow_html: bool,
+    /// comrak_optionで変えたcomrakの拡張
+    comrak_extensions: HashMap<&'static str, bool>,
+    math: bool,
+    split_level: Option<u8>,
+    /// 章を一つのxhtml(content.xhtml)にまとめる
+    merge: bool,
+    toc_title: Option<String>,
+    epub_version: EpubVersion,
+    highlight_theme: String,
     save_tmp_files: bool,
+    /// 変換後に語数・文字数を数える
+    stats: bool,
+    /// 最後に変換した本の語数・文字数
+    text_stats: Option<TextStats>,
+    /// 変換できない章を飛ばして続ける
+    continue_on_error: bool,
+    /// 飛ばした章のpathとエラー
+    failed_files: Vec<(PathBuf, String)>,
+    /// dry-runで組み立てた読む順・manifest・目次
+    plan: Option<String>,
 }
 
 impl Default for RepubBuilder {
     fn default() -> Self {
         RepubBuilder {
             source_file: PathBuf::default(),
+            markdown: Vec::new(),
             tmp_files: TmpFiles::default(),
-            style: Option::default(),
-            id: rand::thread_rng().sample_iter(&Alphanumeric).take(30).collect(),
+            content_dir: String::from("OEBPS"),
+            extensions: MARKDOWN_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            encoding: None,
+            styles: Vec::new(),
+            raw_chapters: Vec::new(),
+            template: None,
+            cover: Option::default(),
+            fonts: Vec::new(),
+            output: Option::default(),
+            output_dir: None,
+            tmp_dir: None,
+            media_types: HashMap::new(),
+            id: Option::default(),
+            deterministic_id: false,
+            deterministic: false,
             title: String::default(),
-            creator: String::default(),
+            creators: Vec::new(),
+            contributors: Vec::new(),
             language: String::default(),
-            vertical: false,
+            languages: Vec::new(),
+            validate_language: true,
+            publisher: Option::default(),
+            rights: Option::default(),
+            series: None,
+            series_index: None,
+            description: Option::default(),
+            subjects: Vec::new(),
+            modified: Option::default(),
+            date: Option::default(),
+            writing_mode: WritingMode::HorizontalTb,
+            direction: None,
+            viewport: None,
+            fixed_layout: false,
+            strict_a11y: false,
+            toc_in_spine: TocPosition::First,
+            flatten_single_chapter: false,
             toc_level: 2,
+            toc_depth: 6,
+            chapter_numbering: None,
+            toc_from: TocSource::Headings,
+            landmarks: false,
+            title_page: false,
+            check: false,
+            dry_run: false,
+            quiet: false,
+            max_depth: None,
+            omit_unlisted: false,
+            hardbreaks: true,
+            smart: false,
+            allow_html: false,
+            comrak_extensions: HashMap::new(),
+            math: false,
+            split_level: None,
+            merge: false,
+            toc_title: Option::default(),
+            epub_version: EpubVersion::default(),
+            highlight_theme: String::from("InspiredGitHub"),
             save_tmp_files: false,
+            stats: false,
+            text_stats: None,
+            continue_on_error: false,
+            failed_files: Vec::new(),
+            plan: None,
         }
     }
 }
 
-struct Package<'a> {
-    metadata: MetaData<'a>,
-    items: Items,
-}
-
-impl<'a> Package<'a> {
-    fn to_opf(&self, vertical: bool) -> String {
-        format!(include_str!("literals/package.opf"), &self.metadata.to_xml(), &self.items.to_manifest(), &self.items.to_spine(vertical))
-    }
-}
-
-struct MetaData<'a> {
-    title: &'a str,
-    creator: &'a str,
-    language: &'a str,
-    id: &'a str,
+/// 出力するEPUBのバージョン
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EpubVersion {
+    /// EPUB 2.0.1 目次はtoc.ncxのみ
+    V2,
+    /// EPUB 3.0.1 目次はnavigation.xhtmlとtoc.ncx
+    #[default]
+    V3,
 }
 
-impl<'a> MetaData<'a> {
-    fn to_xml(&self) -> String {
-        use chrono::prelude::*;
+impl std::str::FromStr for EpubVersion {
+    type Err = failure::Error;
 
-        format!(include_str!("literals/package.opf_metadata"),
-                &self.title,
-                &self.language,
-                &self.creator,
-                &self.id,
-                Utc::now()
-                    .format("%Y-%m-%dT%H:%M:%SZ")
-                    .to_string()
-                    .replace("\"", ""))
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2" | "2.0" | "2.0.1" => Ok(EpubVersion::V2),
+            "3" | "3.0" | "3.0.1" => Ok(EpubVersion::V3),
+            _ => Err(format_err!("[ERROR] {} is not supported EPUB version.", s)),
+        }
     }
 }
 
-#[derive(Default)]
-struct Items {
-    items: Vec<Item>
+/// 書字方向 styles/vertical.cssのwriting-modeになる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritingMode {
+    /// 横書き
+    HorizontalTb,
+    /// 縦書き 行は右から左へ(日本語・中国語など)
+    VerticalRl,
+    /// 縦書き 行は左から右へ(モンゴル語など)
+    VerticalLr,
 }
 
-impl Items {
-    fn to_manifest(&self) -> String {
-        let mut items = String::new();
-        for i in 0..self.items.len() {
-            let item = &self.items[i];
-            items = format!("{}{}\n", items, item.to_manifest(i));
+impl WritingMode {
+    fn as_css(self) -> &'static str {
+        match self {
+            WritingMode::HorizontalTb => "horizontal-tb",
+            WritingMode::VerticalRl => "vertical-rl",
+            WritingMode::VerticalLr => "vertical-lr",
         }
-
-        format!(include_str!("literals/package.opf_manifest"), items)
     }
+}
 
-    fn to_spine(&self, vertical: bool) -> String {
-        let mut items = String::new();
-        for i in 0..self.items.len() {
-            let item = &self.items[i];
-            items = format!("{}{}\n", items, item.to_spine(i));
-        }
+impl std::str::FromStr for WritingMode {
+    type Err = failure::Error;
 
-        if vertical {
-            // 縦書き->右綴じ
-            format!("<spine page-progression-direction=\"rtl\">\n{}\n{}</spine>\n",
-                    "<itemref idref=\"navigation\" />",
-                    items)
-        } else {
-            format!("<spine>\n{}\n{}</spine>\n", "<itemref idref=\"navigation\" />", items)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "horizontal-tb" => Ok(WritingMode::HorizontalTb),
+            "vertical-rl" => Ok(WritingMode::VerticalRl),
+            "vertical-lr" => Ok(WritingMode::VerticalLr),
+            _ => Err(format_err!("[ERROR] {} is not writing mode (horizontal-tb, vertical-rl, vertical-lr).", s)),
         }
     }
 }
 
-struct Item {
-    href: String,
-    media_type: String,
+/// ページ送りの方向(綴じ方向) spineのpage-progression-directionになる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// 左綴じ
+    Ltr,
+    /// 右綴じ
+    Rtl,
+    /// 指定しない(リーダーに任せる)
+    Default,
 }
 
-impl Default for Item {
-    fn default() -> Self {
-        Item {
-            href: "".to_string(),
-            media_type: "application/xhtml+xml".to_string(),
+impl std::str::FromStr for Direction {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ltr" => Ok(Direction::Ltr),
+            "rtl" => Ok(Direction::Rtl),
+            "default" => Ok(Direction::Default),
+            _ => Err(format_err!("[ERROR] {} is not page progression direction (ltr, rtl, default).", s)),
         }
     }
 }
 
-impl Item {
-    /// package.opf内のmanifest要素に変換
-    fn to_manifest(&self, id: usize) -> String {
-        format!("<item id=\"book_{}\" href=\"{}\" media-type=\"{}\" />",
-                id, &self.href, &self.media_type)
-    }
+/// 各ページに書くviewportのmeta要素
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Viewport {
+    /// リフロー型 画面の幅に合わせる
+    DeviceWidth,
+    /// 固定レイアウト用 幅と高さ(px)
+    Fixed { width: u32, height: u32 },
+}
 
-    /// package.opf内のspine要素に変換
-    fn to_spine(&self, id: usize) -> String {
-        format!("<itemref idref=\"book_{}\" />", id)
+impl Viewport {
+    fn as_meta(self) -> String {
+        let content = match self {
+            Viewport::DeviceWidth => String::from("width=device-width"),
+            Viewport::Fixed { width, height } => format!("width={}, height={}", width, height),
+        };
+        format!("<meta name=\"viewport\" content=\"{}\" />", content)
     }
 }
 
+impl std::str::FromStr for Viewport {
+    type Err = failure::Error;
 
-trait ToCTrait {
-    fn get_inner_items(&mut self) -> &mut Vec<ToCItem>;
-
-    fn get_latest(&mut self, level: u8) -> &mut ToCItem;
-
-    fn push(&mut self, toc_item: ToCItem) {
-        self.get_inner_items().push(toc_item);
+    /// "device-width"または"幅x高さ"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "device-width" {
+            return Ok(Viewport::DeviceWidth);
+        }
+        let size = s.find('x').and_then(|index| {
+            let width = s[..index].parse().ok()?;
+            let height = s[index + 1..].parse().ok()?;
+            Some((width, height))
+        });
+        match size {
+            Some((width, height)) if width > 0 && height > 0 => Ok(Viewport::Fixed { width, height }),
+            _ => Err(format_err!("[ERROR] {} is not viewport (device-width or WIDTHxHEIGHT).", s)),
+        }
     }
 }
 
-/// 目次の要素のひとつ
-#[derive(Debug)]
-struct ToCItem {
-    is_dummy: bool,
-    filename: String,
-    id: Option<String>,
-    title: String,
-    level: u8,
-    inner_items: Vec<ToCItem>,
+/// spineでの目次のページ(navigation.xhtml)の位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TocPosition {
+    /// 最初(表紙があれば表紙の次)
+    First,
+    /// 本文の後ろ
+    Last,
+    /// spineに入れない manifestには残るのでリーダーの目次は使える
+    Omitted,
 }
 
-impl Default for ToCItem {
-    fn default() -> Self {
-        ToCItem {
-            is_dummy: true,
-            filename: String::new(),
-            id: None,
-            title: String::new(),
-            level: 1,
-            inner_items: Vec::new(),
+impl std::str::FromStr for TocPosition {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(TocPosition::First),
+            "last" => Ok(TocPosition::Last),
+            "none" => Ok(TocPosition::Omitted),
+            _ => Err(format_err!("[ERROR] {} is not toc position (first, last, none).", s)),
         }
     }
 }
 
-impl ToCTrait for ToCItem {
-    fn get_inner_items(&mut self) -> &mut Vec<ToCItem> {
-        &mut self.inner_items
-    }
-
-    fn get_latest(&mut self, level: u8) -> &mut ToCItem {
-        if level == 1 { return self; }
+/// 目次の項目を何から作るか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TocSource {
+    /// 各ページの見出し
+    Headings,
+    /// 章のファイル1つにつき1項目 タイトルはfront matterかファイル名
+    Files,
+}
 
-        let inner_items = self.get_inner_items();
-        let toc_item = if inner_items.len() == 0 {
-            // initialize
-            inner_items.push(ToCItem::default());
-            inner_items[0].borrow_mut()
-        } else {
-            inner_items.last_mut().unwrap()
-        };
+impl std::str::FromStr for TocSource {
+    type Err = failure::Error;
 
-        toc_item.get_latest(level - 1)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "headings" => Ok(TocSource::Headings),
+            "files" => Ok(TocSource::Files),
+            _ => Err(format_err!("[ERROR] {} is not toc source (headings, files).", s)),
+        }
     }
 }
 
-impl ToCItem {
-    /// xhtml化
-    fn to_nav(&self, level: u8) -> String {
-        let title = if self.is_dummy {
-            String::new()
-        } else {
-            match &self.id {
-                Some(id) => {
-                    format!("<a href=\"{}.xhtml#{}\">{}</a>", &self.filename, id, &self.title)
-                }
-                None => {
-                    format!("<span>{}</span>", &self.title)
-                }
+/// 本文(front matterを除いたmarkdown)の語数と文字数
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TextStats {
+    /// 語数 英数字の並びを1語とし、かなや漢字は1文字を1語と数える
+    pub words: usize,
+    /// 文字数 記号・約物と空白は数えない
+    pub characters: usize,
+}
+
+impl TextStats {
+    fn count(md: &str) -> TextStats {
+        // 分かち書きしない文字 ハングルは空白で区切るので含めない
+        let is_cjk = |c: char| matches!(c,
+            '\u{3040}'..='\u{30ff}' | '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}'
+            | '\u{f900}'..='\u{faff}' | '\u{ff66}'..='\u{ff9f}');
+        let mut stats = TextStats::default();
+        let mut in_word = false;
+        for c in md.chars().filter(|c| !c.is_control()) {
+            if !c.is_alphanumeric() {
+                in_word = false;
+                continue;
             }
-        };
-        let inners: Vec<String> =
-            self.inner_items
-                .iter()
-                .map(|a| a.to_nav(level)).collect();
-        let inners_xhtml = if inners.is_empty() {
-            String::new()
-        } else {
-            if self.level >= level {
-                format!("<ol hidden=\"hidden\">{}</ol>", inners.join(""))
-            } else {
-                format!("<ol>{}</ol>", inners.join(""))
+            stats.characters += 1;
+            if is_cjk(c) {
+                stats.words += 1;
+                in_word = false;
+            } else if !in_word {
+                stats.words += 1;
+                in_word = true;
             }
-        };
+        }
+        stats
+    }
+}
 
-        format!("<li>\n{}\n{}\n</li>\n", &title, &inners_xhtml)
+impl std::ops::AddAssign for TextStats {
+    fn add_assign(&mut self, other: TextStats) {
+        self.words += other.words;
+        self.characters += other.characters;
     }
 }
 
-/// 目次そのもの
-#[derive(Default)]
-struct ToC {
-    inner_items: Vec<ToCItem>
+/// 目次の章番号の書き方 2段目からは"1.2"のように算用数字をつなげる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChapterNumbering {
+    /// 1. 2. 3.
+    Decimal,
+    /// I. II. III.
+    Roman,
+    /// 第一章 第二章 第三章
+    Cjk,
 }
 
-impl ToCTrait for ToC {
-    fn get_inner_items(&mut self) -> &mut Vec<ToCItem> {
-        &mut self.inner_items
+impl ChapterNumbering {
+    /// 章(1段目)の番号
+    fn label(self, n: usize) -> String {
+        match self {
+            ChapterNumbering::Decimal => format!("{}.", n),
+            ChapterNumbering::Roman => match roman_numeral(n) {
+                Some(roman) => format!("{}.", roman),
+                None => format!("{}.", n),
+            },
+            ChapterNumbering::Cjk => format!("第{}章", kanji_numeral(n)),
+        }
     }
+}
 
-    fn get_latest(&mut self, level: u8) -> &mut ToCItem {
-        let inner_items = self.get_inner_items();
-        let toc_item = if inner_items.len() == 0 {
-            // initialize
-            inner_items.push(ToCItem::default());
-            inner_items[0].borrow_mut()
-        } else {
-            inner_items.last_mut().unwrap()
-        };
+impl std::str::FromStr for ChapterNumbering {
+    type Err = failure::Error;
 
-        toc_item.get_latest(level)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "decimal" => Ok(ChapterNumbering::Decimal),
+            "roman" => Ok(ChapterNumbering::Roman),
+            "cjk" => Ok(ChapterNumbering::Cjk),
+            _ => Err(format_err!("[ERROR] {} is not chapter numbering (decimal, roman, cjk).", s)),
+        }
     }
 }
 
-impl ToC {
-    fn new(toc_items: Vec<ToCItem>) -> Self {
-        let mut origin = ToC::default();
-
-        for toc_item in toc_items {
-            let level = toc_item.level;
-            origin.push(toc_item, level);
+/// ローマ数字 1~3999のみ
+fn roman_numeral(n: usize) -> Option<String> {
+    if n == 0 || n > 3999 {
+        return None;
+    }
+    let numerals = [
+        (1000, "M"), (900, "CM"), (500, "D"), (400, "CD"), (100, "C"), (90, "XC"),
+        (50, "L"), (40, "XL"), (10, "X"), (9, "IX"), (5, "V"), (4, "IV"), (1, "I"),
+    ];
+    let mut rest = n;
+    let mut roman = String::new();
+    for (value, numeral) in numerals.iter() {
+        while rest >= *value {
+            roman.push_str(numeral);
+            rest -= value;
         }
-
-        origin
     }
+    Some(roman)
+}
 
-    fn push(&mut self, toc_item: ToCItem, level: u8) {
-        if level == 1 {
-            self.inner_items.push(toc_item);
-        } else {
-            self.get_latest(level - 1).push(toc_item);
+/// 漢数字("十二", "百五") 一万以上は算用数字のまま
+fn kanji_numeral(n: usize) -> String {
+    const DIGITS: [&str; 10] = ["〇", "一", "二", "三", "四", "五", "六", "七", "八", "九"];
+    if n == 0 || n >= 10000 {
+        return n.to_string();
+    }
+    let mut kanji = String::new();
+    for (unit, name) in [(1000, "千"), (100, "百"), (10, "十")].iter() {
+        let digit = n / unit % 10;
+        if digit > 1 {
+            kanji.push_str(DIGITS[digit]);
+        }
+        if digit > 0 {
+            kanji.push_str(name);
         }
     }
-
-    fn to_nav(&self, level: u8, vertical: bool, title: Option<String>) -> String {
-        let inners: Vec<String> =
-            self.inner_items
-                .iter()
-                .map(|a| a.to_nav(level)).collect();
-        let inners_xhtml = if inners.is_empty() {
-            String::new()
-        } else {
-            inners.join("")
-        };
-        let title = title.unwrap_or(String::new());
-        format!(include_str!("literals/navigation.xhtml"),
-                &title,
-                if vertical {
-                    "<link type=\"text/css\" rel=\"stylesheet\" href=\"styles/vertical.css\" />"
-                } else { "" },
-                &title,
-                &inners_xhtml)
+    match n % 10 {
+        0 => {}
+        ones => kanji.push_str(DIGITS[ones]),
     }
+    kanji
 }
 
-impl RepubBuilder {
-    /// 絶対パス、あるいは相対パスでソースを指定してRepubBuilderを得る
-    pub fn new(path: &Path, matches: &ArgMatches) -> Result<RepubBuilder, failure::Error> {
-        // コマンドの実行path
-        let origin = &std::env::current_dir()?;
+struct Package<'a> {
+    metadata: MetaData<'a>,
+    items: Items,
+    navigation: TocPosition,
+}
 
-        let md_path = if path.is_absolute() {
-            path.to_path_buf()
-        } else {
-            // 指定されたディレクトリへのpath
-            origin.join(path)
-        };
+impl<'a> Package<'a> {
+    /// dry-run用 読む順・manifest・目次の一覧
+    fn to_plan(&self, toc: &ToC) -> String {
+        let mut plan = String::from("spine:\n");
+        for i in self.items.reading_order() {
+            let item = &self.items.items[i];
+            let linear = if item.linear { "" } else { " (linear=\"no\")" };
+            plan.push_str(&format!("    {}{}\n", item.href, linear));
+        }
 
-        // 存在しないpath
-        if !md_path.exists() {
-            return Err(format_err!("[ERROR] {:?} does not exist.", &md_path));
+        plan.push_str("manifest:\n");
+        for (i, item) in self.items.items.iter().enumerate() {
+            let properties = item.properties.as_ref().map_or(String::new(), |properties| format!(" [{}]", properties));
+            plan.push_str(&format!("    {} {} {}{}\n", item.id(i), item.href, item.media_type, properties));
         }
 
-        // .mdファイルorディレクトリではない
-        if md_path.is_file() {
-            match md_path.extension() {
-                None => {}
-                Some(ext) => {
-                    if ext != "md" {
-                        return Err(format_err!("[ERROR] {:?} is not .md file.", &md_path));
-                    }
-                }
-            }
+        plan.push_str("toc:\n");
+        for toc_item in &toc.inner_items {
+            plan.push_str(&toc_item.to_plan(1));
         }
+        plan
+    }
 
-        let mut repub_builder = RepubBuilder {
-            source_file: md_path,
-            vertical: matches.is_present("vertical"),
-            save_tmp_files: matches.is_present("save_tmp_files"),
-            ..RepubBuilder::default()
-        };
+    fn to_opf(&self, direction: Direction, version: EpubVersion) -> String {
+        let metadata = self.metadata.to_xml(version);
+        let manifest = self.items.to_manifest(version);
+        let spine = self.items.to_spine(direction, version, self.metadata.fixed_layout, self.navigation);
+        match version {
+            EpubVersion::V2 => format!(include_str!("literals/package2.opf"), metadata, manifest, spine),
+            EpubVersion::V3 => format!(include_str!("literals/package.opf"), metadata, manifest, spine),
+        }
+    }
+}
 
-        // タイトル
-        if let Some(title) = matches.value_of("title") {
-            repub_builder.titled(title);
-        } else {
-            print!("Title: ");
-            std::io::stdout().flush().context("Failed to read line.")?;
+/// 作者、編集者、翻訳者など
+#[derive(Debug, Clone)]
+pub struct Creator {
+    name: String,
+    /// MARC relatorコード
+    role: String,
+}
 
-            let mut title = String::new();
-            std::io::stdin().read_line(&mut title)
-                .expect("Failed to read line");
-            repub_builder.titled(title.trim());
-        }
+impl Creator {
+    /// "名前:役割"の形式から読み取る 役割の指定がなければ著者(aut)とする
+    pub fn parse(text: &str) -> Creator {
+        Creator::parse_with_role(text, "aut")
+    }
 
-        // 作者,編集者,著者
-        if let None = matches.value_of("creator") {
-            print!("Creator: ");
-            std::io::stdout().flush().context("Failed to read line.")?;
+    /// 役割の指定がなければdefault_roleとする
+    fn parse_with_role(text: &str, default_role: &str) -> Creator {
+        let text = text.trim();
+        if let Some(index) = text.rfind(':') {
+            let role = &text[index + 1..];
+            // relatorコードは英小文字3文字
+            if role.len() == 3 && role.chars().all(|c| c.is_ascii_lowercase()) {
+                return Creator {
+                    name: text[..index].trim().to_string(),
+                    role: role.to_string(),
+                };
+            }
+        }
 
-            let mut creator = String::new();
-            std::io::stdin().read_line(&mut creator)
-                .expect("Failed to read line");
-            repub_builder.creator(creator.trim());
+        Creator {
+            name: text.to_string(),
+            role: default_role.to_string(),
         }
+    }
+}
+
+struct MetaData<'a> {
+    title: &'a str,
+    creators: &'a [Creator],
+    /// 挿絵、装丁、校正など
+    contributors: &'a [Creator],
+    language: &'a str,
+    languages: &'a [String],
+    publisher: Option<&'a str>,
+    /// 著作権表示
+    rights: Option<&'a str>,
+    /// シリーズ名と巻数
+    series: Option<&'a str>,
+    series_index: Option<&'a str>,
+    description: Option<&'a str>,
+    subjects: &'a [String],
+    id: &'a str,
+    modified: DateTime<Utc>,
+    /// 出版日
+    date: Option<&'a str>,
+    /// 表紙画像のmanifest id
+    cover: Option<&'a str>,
+    /// 固定レイアウト
+    fixed_layout: bool,
+}
 
-        // 言語
-        if let None = matches.value_of("language") {
-            print!("Language: ");
-            std::io::stdout().flush().context("Failed to read line.")?;
+impl<'a> MetaData<'a> {
+    fn to_xml(&self, version: EpubVersion) -> String {
+        let mut elements = vec![
+            format!("<dc:title>{}</dc:title>", escape_xml(self.title)),
+            format!("<dc:language>{}</dc:language>", escape_xml(self.language)),
+        ];
+        for language in self.languages {
+            elements.push(format!("<dc:language>{}</dc:language>", escape_xml(language)));
+        }
 
-            let mut language = String::new();
-            std::io::stdin().read_line(&mut language)
-                .expect("Failed to read line");
-            repub_builder.language(language.trim());
+        for (element, people) in [("creator", self.creators), ("contributor", self.contributors)].iter() {
+            for (i, person) in people.iter().enumerate() {
+                match version {
+                    // EPUB2では役割は属性で書く
+                    EpubVersion::V2 => {
+                        elements.push(format!("<dc:{0} opf:role=\"{1}\">{2}</dc:{0}>", element, escape_xml(&person.role), escape_xml(&person.name)));
+                    }
+                    EpubVersion::V3 => {
+                        elements.push(format!("<dc:{0} id=\"{0}_{1}\">{2}</dc:{0}>", element, i, escape_xml(&person.name)));
+                        elements.push(format!("<meta refines=\"#{}_{}\" property=\"role\" scheme=\"marc:relators\">{}</meta>",
+                                              element, i, escape_xml(&person.role)));
+                    }
+                }
+            }
         }
 
-        if let Some(id) = matches.value_of("book_id") {
-            println!("Book ID: {}", id);
-            repub_builder.book_id(id);
+        // 任意の要素は指定されたときだけ書き出す
+        if let Some(publisher) = &self.publisher {
+            elements.push(format!("<dc:publisher>{}</dc:publisher>", escape_xml(publisher)));
+        }
+        if let Some(rights) = &self.rights {
+            elements.push(format!("<dc:rights>{}</dc:rights>", escape_xml(rights)));
+        }
+        if let Some(description) = &self.description {
+            // 改行はそのまま残す
+            elements.push(format!("<dc:description>{}</dc:description>", escape_xml(description)));
+        }
+        for subject in self.subjects {
+            elements.push(format!("<dc:subject>{}</dc:subject>", escape_xml(subject)));
         }
 
-        // css style
-        if let Some(css) = matches.value_of("style") {
-            repub_builder.style(origin.join(css));
+        if let Some(date) = &self.date {
+            match version {
+                EpubVersion::V2 => elements.push(format!("<dc:date opf:event=\"publication\">{}</dc:date>", escape_xml(date))),
+                EpubVersion::V3 => elements.push(format!("<dc:date>{}</dc:date>", escape_xml(date))),
+            }
         }
 
-        // toc_level
-        if let Some(level) = matches.value_of("toc_level") {
-            repub_builder.toc_level = match level.parse::<u8>() {
-                Ok(ok) => ok - 1,
-                Err(_) => {
-                    println!("Warning {} は目次のレベルに設定できません", &level);
-                    2
+        elements.append(&mut self.identifier_xml(version));
+        elements.push(self.modified_xml(version));
+        if let Some(cover) = &self.cover {
+            elements.push(format!("<meta name=\"cover\" content=\"{}\" />", cover));
+        }
+        if let Some(series) = &self.series {
+            // Calibreはこちらを読む
+            elements.push(format!("<meta name=\"calibre:series\" content=\"{}\" />", escape_xml(series)));
+            if let Some(index) = &self.series_index {
+                elements.push(format!("<meta name=\"calibre:series_index\" content=\"{}\" />", escape_xml(index)));
+            }
+            if version == EpubVersion::V3 {
+                elements.push(format!("<meta property=\"belongs-to-collection\" id=\"series\">{}</meta>", escape_xml(series)));
+                elements.push(String::from("<meta refines=\"#series\" property=\"collection-type\">series</meta>"));
+                if let Some(index) = &self.series_index {
+                    elements.push(format!("<meta refines=\"#series\" property=\"group-position\">{}</meta>", escape_xml(index)));
                 }
-            };
+            }
+        }
+        if self.fixed_layout && version == EpubVersion::V3 {
+            elements.push(String::from("<meta property=\"rendition:layout\">pre-paginated</meta>"));
         }
 
-        Ok(repub_builder)
-    }
+        let elements: String = elements.iter()
+            .map(|element| format!("    {}\n", element))
+            .collect();
 
-    pub fn titled(&mut self, title: &str) -> &mut Self {
-        self.title = title.to_string();
-        self
+        format!(include_str!("literals/package.opf_metadata"), elements)
     }
 
-    pub fn creator(&mut self, creator: &str) -> &mut Self {
-        self.creator = creator.to_string();
-        self
-    }
+    /// 識別子 urn:isbn:やurn:uuid:で始まるものはschemeを付ける
+    fn identifier_xml(&self, version: EpubVersion) -> Vec<String> {
+        let id = self.id;
+        let (scheme, value) = if let Some(isbn) = id.strip_prefix("urn:isbn:") {
+            (Some("ISBN"), isbn)
+        } else if let Some(uuid) = id.strip_prefix("urn:uuid:") {
+            (Some("UUID"), uuid)
+        } else {
+            (None, id)
+        };
 
-    pub fn language(&mut self, language: &str) -> &mut Self {
-        self.language = language.to_string();
-        self
+        match (version, scheme) {
+            // EPUB2では属性で書き、値はurnを外す
+            (EpubVersion::V2, Some(scheme)) => vec![
+                format!("<dc:identifier id=\"BookId\" opf:scheme=\"{}\">{}</dc:identifier>", scheme, escape_xml(value)),
+            ],
+            // EPUB3ではurnのまま書き、ISBNはONIXのコードで種類を示す
+            (EpubVersion::V3, Some("ISBN")) => vec![
+                format!("<dc:identifier id=\"BookId\">{}</dc:identifier>", escape_xml(id)),
+                format!("<meta refines=\"#BookId\" property=\"identifier-type\" scheme=\"onix:codelist5\">{}</meta>",
+                        if value.len() == 10 { "02" } else { "15" }),
+            ],
+            _ => vec![
+                format!("<dc:identifier id=\"BookId\">{}</dc:identifier>", escape_xml(id)),
+            ],
+        }
     }
 
-    pub fn style(&mut self, style: PathBuf) -> &mut Self {
-        self.style = Some(style);
-        self
-    }
+    /// 更新日時 EPUB3の要求通りCCYY-MM-DDThh:mm:ssZの形式で秒まで書く
+    fn modified_xml(&self, version: EpubVersion) -> String {
+        use chrono::SecondsFormat;
 
-    pub fn book_id(&mut self, book_id: &str) -> &mut Self {
-        self.id = book_id.to_string();
-        self
+        let modified = self.modified.to_rfc3339_opts(SecondsFormat::Secs, true);
+        match version {
+            EpubVersion::V2 => format!("<dc:date opf:event=\"modification\">{}</dc:date>", modified),
+            EpubVersion::V3 => format!("<meta property=\"dcterms:modified\">{}</meta>", modified),
+        }
     }
+}
 
-    /// mimetypeファイルを配置する
-    fn add_mimetype(&mut self, dir_path: &PathBuf) -> Result<(), failure::Error> {
-        // pathを作成
-        let mimetype_path = dir_path.join("mimetype");
-        // ファイルを作成
-        let mut mimetype = File::create(&mimetype_path)?;
-        // 書き込み
-        mimetype.write_all(include_str!("literals/mimetype").as_bytes())?;
+/// 標準でmarkdownとして読む拡張子
+const MARKDOWN_EXTENSIONS: [&str; 4] = ["md", "markdown", "mdown", "mkd"];
 
-        self.tmp_files.mimetype = Some(mimetype_path);
+/// 拡張子がextensionsのどれかか 大文字小文字は区別しない
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .filter(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+        .is_some()
+}
 
-        Ok(())
+/// ディレクトリ以下のmarkdownファイルを深さ優先で集める
+/// 各ディレクトリ内は名前順に並べる _で始まるファイルはincludeで読む部品なので除く
+/// * max_depth - 潜るサブディレクトリの深さ Noneなら制限なし
+/// * extensions - markdownとして読む拡張子
+fn markdown_files(dir: &Path, max_depth: Option<usize>, extensions: &[String]) -> Result<Vec<PathBuf>, failure::Error> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?
+        .collect::<Result<_, _>>()?;
+    // 並べ替え
+    entries.sort_by_key(|e: &std::fs::DirEntry| e.path());
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            match max_depth {
+                Some(0) => {}
+                Some(depth) => paths.append(&mut markdown_files(&path, Some(depth - 1), extensions)?),
+                None => paths.append(&mut markdown_files(&path, None, extensions)?),
+            }
+        } else if has_extension(&path, extensions) && !entry.file_name().to_string_lossy().starts_with('_') {
+            paths.push(path);
+        }
     }
 
-    /// META-INFフォルダを配置する
-    fn add_meta_inf(&mut self, dir_path: &PathBuf) -> Result<(), failure::Error> {
-        // META-INFフォルダのpathを作成
-        let meta_inf = dir_path.join("META-INF");
-        // フォルダを作成
-        std::fs::create_dir_all(&meta_inf)?;
+    Ok(paths)
+}
 
-        // container.xmlを作成
-        let mut container = File::create(
-            meta_inf.join("container.xml"))?;
-        // 書き込み
-        container.write_all(include_str!("literals/container.xml").as_bytes())?;
+/// mdファイルを読んでUTF-8にする BOMがあればそれに従う
+/// * encoding - 文字コード Noneならguess_encodingで推測し、推測が外れていそうならエラー
+fn read_markdown(path: &Path, encoding: Option<&'static Encoding>) -> Result<String, failure::Error> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| format_err!("[ERROR] failed to read {:?} : {}", path, e))?;
+    match encoding {
+        Some(encoding) => match encoding.decode(&bytes) {
+            (text, _, false) => Ok(text.into_owned()),
+            (_, encoding, true) => Err(format_err!("[ERROR] {:?} is not valid {} text.", path, encoding.name())),
+        },
+        None => {
+            let guessed = guess_encoding(&bytes);
+            let (text, encoding, had_errors) = guessed.decode(&bytes);
+            // 制御文字が混じるのは文字コードを取り違えたとき
+            if had_errors || text.chars().any(|c| ('\u{80}'..='\u{9f}').contains(&c)) {
+                return Err(format_err!("[ERROR] could not detect the encoding of {:?} (guessed {}). Please specify the encoding.",
+                                       path, encoding.name()));
+            }
+            if encoding != encoding_rs::UTF_8 {
+                debug!("{} を{}として読みます", path.display(), encoding.name());
+            }
+            Ok(text.into_owned())
+        }
+    }
+}
+
+/// 文字コードを推測する BOM、UTF-8、日本語の文字コード(Shift_JIS, EUC-JP)の順に試し、
+/// どれでもなければwindows-1252(Latin-1)とする
+/// 日本語の文字コードはどちらとしても読めることがあるので、かなや漢字が多く読めるほうを選ぶ
+fn guess_encoding(bytes: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return encoding_rs::UTF_8;
+    }
+    let japanese = |c: &char| matches!(*c, '\u{3040}'..='\u{30ff}' | '\u{4e00}'..='\u{9fff}');
+    [encoding_rs::SHIFT_JIS, encoding_rs::EUC_JP].iter()
+        .filter_map(|encoding| match encoding.decode_without_bom_handling(bytes) {
+            (text, false) => Some((text.chars().filter(japanese).count(), *encoding)),
+            (_, true) => None,
+        })
+        .filter(|(count, _)| *count > 0)
+        .max_by_key(|(count, _)| *count)
+        .map_or(encoding_rs::WINDOWS_1252, |(_, encoding)| encoding)
+}
+
+/// `{% include "path.md" %}`の行ならそのpath
+fn include_target(line: &str) -> Option<&str> {
+    let directive = line.trim().strip_prefix("{%")?.strip_suffix("%}")?.trim();
+    let target = directive.strip_prefix("include")?.trim();
+    target.strip_prefix('"')?.strip_suffix('"')
+}
+
+/// `{% include "path.md" %}`だけの行を、そのファイルの中身(front matterを除く)に置き換える
+/// pathは含める側のファイルからの相対path コードブロックの中は置き換えない
+/// * encoding - 含めるファイルの文字コード Noneなら推測する
+/// * stack - 展開中のファイル 同じファイルに戻ってきたらエラー
+fn expand_includes(md: &str, source_path: &Path, encoding: Option<&'static Encoding>, stack: &mut Vec<PathBuf>) -> Result<String, failure::Error> {
+    stack.push(source_path.canonicalize().unwrap_or_else(|_| source_path.to_path_buf()));
+    let dir = source_path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut expanded = String::with_capacity(md.len());
+    // 開いているコードブロックの記号(```か~~~)
+    let mut fence: Option<&str> = None;
+    for line in md.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        match fence {
+            Some(marker) if trimmed.starts_with(marker) => fence = None,
+            None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => fence = Some(&trimmed[..3]),
+            _ => {}
+        }
+        let target = match include_target(line) {
+            Some(target) if fence.is_none() => target,
+            _ => {
+                expanded.push_str(line);
+                continue;
+            }
+        };
+
+        let path = dir.join(target);
+        if !path.is_file() {
+            return Err(format_err!("[ERROR] {:?} included from {:?} does not exist.", path, source_path));
+        }
+        if stack.contains(&path.canonicalize()?) {
+            return Err(format_err!("[ERROR] {:?} includes {:?} recursively.", source_path, path));
+        }
+        let partial = read_markdown(&path, encoding)?;
+        let (_, body) = FrontMatter::split(&partial, &path)?;
+        let mut included = expand_includes(body, &path, encoding, stack)?;
+        if !included.ends_with('\n') {
+            included.push('\n');
+        }
+        expanded.push_str(&included);
+    }
+
+    stack.pop();
+    Ok(expanded)
+}
+
+/// ファイル名に使えない文字を置き換える
+fn sanitize_filename(name: &str) -> String {
+    let name: String = name.trim().chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // 空や"."で始まる名前は避ける
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        String::from("book")
+    } else {
+        name.to_string()
+    }
+}
+
+/// PATHにあるepubcheckで.epubを検査する
+/// epubcheckがなければ警告を出して成功扱いにする
+fn check_with_epubcheck(epub_path: &Path) -> Result<(), failure::Error> {
+    use std::process::Command;
+
+    let output = match Command::new("epubcheck").arg(epub_path).output() {
+        Ok(output) => output,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("epubcheck が見つからないため検査しません");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // 結果はそのまま見せる
+    std::io::stdout().write_all(&output.stdout)?;
+    std::io::stderr().write_all(&output.stderr)?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format_err!("[ERROR] epubcheck found errors in {:?}.", epub_path))
+    }
+}
+
+/// 作業ディレクトリへの書き込み dry-runのときは何もしない
+#[derive(Debug, Clone, Copy)]
+struct Staging {
+    dry_run: bool,
+}
+
+impl Staging {
+    fn create_dir(self, path: &Path) -> std::io::Result<()> {
+        if self.dry_run { return Ok(()); }
+        std::fs::create_dir_all(path)
+    }
+
+    fn write(self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        if self.dry_run { return Ok(()); }
+        File::create(path)?.write_all(contents)
+    }
+
+    fn copy(self, from: &Path, to: &Path) -> std::io::Result<()> {
+        if self.dry_run { return Ok(()); }
+        std::fs::copy(from, to).map(|_| ())
+    }
+
+    fn remove_file(self, path: &Path) -> std::io::Result<()> {
+        if self.dry_run { return Ok(()); }
+        std::fs::remove_file(path)
+    }
+}
+
+/// 作業ディレクトリの中身を.epubとして書き出す
+fn write_epub(epub: File, mimetype: &Path, meta_inf: &Path, oebps: &Path, modified: DateTime<Utc>) -> ZipResult<()> {
+    use zip::write::{FileOptions, ZipWriter};
+
+    let mut writer = ZipWriter::new(epub);
+    // 中のファイルの日時は更新日時にそろえる
+    let options = FileOptions::default().last_modified_time(zip_date_time(modified));
+
+    // mimetype 先頭に無圧縮で、パスを含めない名前で置く
+    {
+        writer.start_file("mimetype",
+                          options.compression_method(CompressionMethod::Stored))?;
+        writer.write_all(&std::fs::read(mimetype)?)?;
+    }
+
+    // META-INF, OEBPS 名前は作業ディレクトリからの相対path
+    let root = oebps.parent().unwrap_or_else(|| Path::new(""));
+    zip_dir(&mut writer, meta_inf, root, options)?;
+    zip_dir(&mut writer, oebps, root, options)?;
+
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// ディレクトリとその中身をzipに加える
+fn zip_dir<W: Write + std::io::Seek>(writer: &mut zip::ZipWriter<W>, dir: &Path, root: &Path, options: zip::write::FileOptions) -> ZipResult<()> {
+    let options = options.compression_method(CompressionMethod::Deflated);
+    writer.add_directory(format!("{}/", archive_name(dir, root)), options)?;
+
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            zip_dir(writer, &path, root, options)?;
+        } else if path.is_file() {
+            writer.start_file(archive_name(&path, root), options)?;
+            writer.write_all(&std::fs::read(&path)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// 中身のフォルダのファイルがすべてmanifestに載っているか確かめる
+/// 載っていないファイルが.epubに入ると仕様違反になる
+/// * manifest - package.opfのmanifest要素
+fn check_manifest(dir: &Path, oebps_path: &Path, manifest: &str) -> Result<(), failure::Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            check_manifest(&path, oebps_path, manifest)?;
+            continue;
+        }
+        let href = archive_name(&path, oebps_path);
+        if href != PACKAGE_FILE && !manifest.contains(&format!("href=\"{}\"", escape_xml(&href))) {
+            return Err(format_err!("[ERROR] {} is not listed in the manifest.", href));
+        }
+    }
+    Ok(())
+}
+
+/// zipの日時 1980年より前は扱えないので1980-01-01にする
+fn zip_date_time(date_time: DateTime<Utc>) -> zip::DateTime {
+    use chrono::{Datelike, Timelike};
+
+    zip::DateTime::from_date_and_time(date_time.year() as u16, date_time.month() as u8, date_time.day() as u8,
+                                      date_time.hour() as u8, date_time.minute() as u8, date_time.second() as u8)
+        .unwrap_or_default()
+}
+
+/// zip内の名前 rootからの相対pathを/で区切る
+fn archive_name(path: &Path, root: &Path) -> String {
+    use std::path::Component;
+
+    path.strip_prefix(root).unwrap_or(path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// pathのファイル名 ない、またはUTF-8でなければエラー
+fn file_name_of(path: &Path) -> Result<&str, failure::Error> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format_err!("[ERROR] {:?} does not have a UTF-8 file name.", path))
+}
+
+/// pathの拡張子を除いたファイル名 ない、またはUTF-8でなければエラー
+fn file_stem_of(path: &Path) -> Result<&str, failure::Error> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| format_err!("[ERROR] {:?} does not have a UTF-8 file name.", path))
+}
+
+/// 目次に載せるためにファイル名を読みやすくする
+/// 並べるための先頭の番号を除き、_と-を空白にする("01_first-steps" -> "first steps")
+fn humanize_file_stem(stem: &str) -> String {
+    let trimmed = stem.trim_start_matches(|c: char| c.is_ascii_digit() || c == '_' || c == '-' || c == '.' || c == ' ');
+    let humanized = trimmed.replace(&['_', '-'][..], " ");
+    let humanized = humanized.trim();
+    if humanized.is_empty() {
+        stem.to_string()
+    } else {
+        humanized.to_string()
+    }
+}
+
+/// ファイル名だけで意味が決まるページのepub:type
+const EPUB_TYPES_BY_NAME: [&str; 12] = [
+    "preface", "foreword", "prologue", "introduction", "epilogue", "afterword",
+    "appendix", "glossary", "bibliography", "acknowledgments", "dedication", "colophon",
+];
+
+/// ページのepub:type front matterのtype、なければ番号を除いたファイル名が
+/// EPUB_TYPES_BY_NAMEにあればそれ、どちらでもなければchapter
+fn epub_type_of(front_matter: &FrontMatter, source_path: &Path) -> Result<String, failure::Error> {
+    if let Some(epub_type) = &front_matter.epub_type {
+        return Ok(epub_type.clone());
+    }
+    let name = humanize_file_stem(file_stem_of(source_path)?).to_lowercase();
+    Ok(EPUB_TYPES_BY_NAME.iter()
+        .find(|epub_type| **epub_type == name)
+        .map_or("chapter", |epub_type| *epub_type)
+        .to_string())
+}
+
+/// ページのepub:typeが本のどの部分にあたるか bodyのepub:typeになる
+fn division_of(epub_type: &str) -> &'static str {
+    match epub_type {
+        "titlepage" | "preface" | "foreword" | "prologue" | "introduction" | "acknowledgments" | "dedication" | "frontmatter" => "frontmatter",
+        "epilogue" | "afterword" | "appendix" | "glossary" | "bibliography" | "colophon" | "backmatter" => "backmatter",
+        _ => "bodymatter",
+    }
+}
+
+/// mdファイル名からxhtmlのファイル名(拡張子なし)を決める
+/// 小文字にして[a-z0-9_-]以外の並びを-に置き換え、使用済みの名前なら_2, _3...を付ける
+fn xhtml_name(stem: &str, items: &Items) -> String {
+    let mut name = String::with_capacity(stem.len());
+    for c in stem.to_lowercase().chars() {
+        match c {
+            'a'..='z' | '0'..='9' | '_' | '-' => name.push(c),
+            _ => if !name.ends_with('-') { name.push('-') },
+        }
+    }
+    let name = name.trim_matches('-');
+    let name = if name.is_empty() { "chapter" } else { name };
+
+    // navigation.xhtmlは目次が使う
+    let taken = |candidate: &str| {
+        let href = format!("{}.xhtml", candidate);
+        href == "navigation.xhtml" || items.items.iter().any(|item| item.href == href)
+    };
+    if !taken(name) {
+        return name.to_string();
+    }
+    (2..).map(|n| format!("{}_{}", name, n))
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+/// XMLの特殊文字をエスケープする
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Default)]
+struct Items {
+    items: Vec<Item>
+}
+
+impl Items {
+    fn to_manifest(&self, version: EpubVersion) -> String {
+        let mut items = String::new();
+        for i in 0..self.items.len() {
+            let item = &self.items[i];
+            items = format!("{}{}\n", items, item.to_manifest(i, version));
+        }
+
+        // navigation.xhtmlはEPUB3のみ
+        let navigation = match version {
+            EpubVersion::V2 => "",
+            EpubVersion::V3 => "<item id=\"navigation\" href=\"navigation.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\" />\n",
+        };
+
+        format!(include_str!("literals/package.opf_manifest"), navigation, items)
+    }
+
+    /// 読む順 基本はmanifestの順で、spine_positionのあるページはその位置に移す
+    /// * return - itemsのindex
+    fn reading_order(&self) -> Vec<usize> {
+        // 画像などはspineに含めない
+        let mut order: Vec<usize> = (0..self.items.len())
+            .filter(|i| self.items[*i].media_type == "application/xhtml+xml")
+            .collect();
+
+        let mut positioned: Vec<(usize, usize)> = order.iter()
+            .filter_map(|i| self.items[*i].spine_position.map(|position| (position, *i)))
+            .collect();
+        positioned.sort();
+        order.retain(|i| self.items[*i].spine_position.is_none());
+        for (position, i) in positioned {
+            // 1始まり 範囲外は末尾
+            let index = position.saturating_sub(1).min(order.len());
+            order.insert(index, i);
+        }
+        order
+    }
+
+    fn to_spine(&self, direction: Direction, version: EpubVersion, fixed_layout: bool, navigation_position: TocPosition) -> String {
+        // 固定レイアウトでは見開きの左右を交互に割り当てる 最初のページは綴じ方向の反対側
+        let spreads = match direction {
+            Direction::Rtl => ["rendition:page-spread-left", "rendition:page-spread-right"],
+            _ => ["rendition:page-spread-right", "rendition:page-spread-left"],
+        };
+        let order = self.reading_order();
+        let mut itemrefs: Vec<String> = order.iter().enumerate()
+            .map(|(n, i)| {
+                let spread = if fixed_layout { Some(spreads[n % 2]) } else { None };
+                self.items[*i].to_spine(*i, spread)
+            })
+            .collect();
+
+        if version == EpubVersion::V2 {
+            // EPUB2にはnavigation.xhtmlもページ送り方向もない
+            let items: String = itemrefs.iter().map(|itemref| format!("{}\n", itemref)).collect();
+            return format!("<spine toc=\"ncx\">\n{}</spine>\n", items);
+        }
+
+        let direction = match direction {
+            Direction::Ltr => " page-progression-direction=\"ltr\"",
+            Direction::Rtl => " page-progression-direction=\"rtl\"",
+            Direction::Default => "",
+        };
+        // 目次はviewportを持たないのでリフロー型のまま
+        let navigation = if fixed_layout {
+            "<itemref idref=\"navigation\" properties=\"rendition:layout-reflowable\" />"
+        } else {
+            "<itemref idref=\"navigation\" />"
+        };
+        match navigation_position {
+            TocPosition::First => {
+                // 表紙や扉から始まる本では、目次はその次に置く
+                let opening = order.iter()
+                    .take_while(|i| matches!(self.items[**i].id.as_deref(), Some("cover") | Some("titlepage")))
+                    .count();
+                itemrefs.insert(opening, navigation.to_string());
+            }
+            TocPosition::Last => itemrefs.push(navigation.to_string()),
+            TocPosition::Omitted => {}
+        }
+        let items: String = itemrefs.iter().map(|itemref| format!("{}\n", itemref)).collect();
+        format!("<spine toc=\"ncx\"{}>\n{}</spine>\n", direction, items)
+    }
+}
+
+struct Item {
+    /// 指定がなければ"book_{番号}"
+    id: Option<String>,
+    href: String,
+    media_type: String,
+    properties: Option<String>,
+    /// spineでの位置(1始まり) 指定がなければmanifestの順
+    spine_position: Option<usize>,
+    /// falseならページ送りで飛ばす(linear="no") リンクからは開ける
+    linear: bool,
+    /// 本文のページのepub:type
+    epub_type: Option<String>,
+}
+
+impl Default for Item {
+    fn default() -> Self {
+        Item {
+            id: None,
+            href: "".to_string(),
+            media_type: "application/xhtml+xml".to_string(),
+            properties: None,
+            spine_position: None,
+            linear: true,
+            epub_type: None,
+        }
+    }
+}
+
+impl Item {
+    fn id(&self, index: usize) -> String {
+        self.id.clone().unwrap_or_else(|| format!("book_{}", index))
+    }
+
+    /// package.opf内のmanifest要素に変換
+    /// properties属性はEPUB3のみ EPUB2の表紙は<meta name="cover">で示す
+    fn to_manifest(&self, index: usize, version: EpubVersion) -> String {
+        let properties = match (&self.properties, version) {
+            (Some(properties), EpubVersion::V3) => format!(" properties=\"{}\"", properties),
+            _ => String::new(),
+        };
+        format!("<item id=\"{}\" href=\"{}\" media-type=\"{}\"{} />",
+                escape_xml(&self.id(index)), escape_xml(&self.href), &self.media_type, properties)
+    }
+
+    /// package.opf内のspine要素に変換
+    /// * properties - 固定レイアウトの見開きの位置など
+    fn to_spine(&self, index: usize, properties: Option<&str>) -> String {
+        let linear = if self.linear { "" } else { " linear=\"no\"" };
+        let properties = properties.map_or(String::new(), |properties| format!(" properties=\"{}\"", properties));
+        format!("<itemref idref=\"{}\"{}{} />", self.id(index), linear, properties)
+    }
+}
+
+/// 拡張子(小文字)からmedia-typeを得る 知らない拡張子はNone
+fn media_type_for(ext: &str) -> Option<&'static str> {
+    match ext {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "svg" => Some("image/svg+xml"),
+        "webp" => Some("image/webp"),
+        "ttf" | "otf" => Some("application/vnd.ms-opentype"),
+        "woff" => Some("application/font-woff"),
+        "woff2" => Some("font/woff2"),
+        "mp3" => Some("audio/mpeg"),
+        "m4a" | "mp4" => Some("audio/mp4"),
+        "css" => Some("text/css"),
+        "xhtml" => Some("application/xhtml+xml"),
+        "js" => Some("text/javascript"),
+        "ncx" => Some("application/x-dtbncx+xml"),
+        _ => None,
+    }
+}
+
+/// pathのmedia-type overridesに拡張子があればそちらを使う
+/// どちらにもなければapplication/octet-streamとして警告する
+fn media_type_of(path: &Path, overrides: &HashMap<String, String>) -> String {
+    let ext = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default();
+    if let Some(media_type) = overrides.get(&ext) {
+        return media_type.clone();
+    }
+    match media_type_for(&ext) {
+        Some(media_type) => media_type.to_string(),
+        None => {
+            warn!("{} のmedia-typeがわかりません application/octet-streamとして扱います", path.display());
+            String::from("application/octet-stream")
+        }
+    }
+}
+
+/// 画像のmedia-type 画像でなければエラー
+fn image_media_type(path: &Path, overrides: &HashMap<String, String>) -> Result<String, failure::Error> {
+    let media_type = media_type_of(path, overrides);
+    if media_type.starts_with("image/") {
+        Ok(media_type)
+    } else {
+        Err(format_err!("[ERROR] {:?} is not supported image.", path))
+    }
+}
+
+/// 8-4-4-4-12桁の16進数か
+fn is_uuid(text: &str) -> bool {
+    let groups: Vec<&str> = text.split('-').collect();
+    groups.len() == 5 && groups.iter().zip(&[8, 4, 4, 4, 12]).all(|(group, len)| {
+        group.len() == *len && group.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+/// ハイフンと空白を除いたISBN チェックディジットが合わなければエラー
+fn isbn_digits(isbn: &str) -> Result<String, failure::Error> {
+    let digits: String = isbn.chars().filter(|c| *c != '-' && !c.is_whitespace()).collect();
+    let invalid = || format_err!("[ERROR] {} is not valid ISBN.", isbn);
+
+    let valid = match digits.len() {
+        10 => {
+            // 末尾のXは10
+            let sum = digits.chars().enumerate().try_fold(0, |sum, (i, c)| {
+                let value = match c {
+                    'X' | 'x' if i == 9 => 10,
+                    c => c.to_digit(10)?,
+                };
+                Some(sum + value * (10 - i as u32))
+            });
+            sum.map(|sum| sum % 11) == Some(0)
+        }
+        13 => {
+            let sum = digits.chars().enumerate().try_fold(0, |sum, (i, c)| {
+                let weight = if i % 2 == 0 { 1 } else { 3 };
+                Some(sum + c.to_digit(10)? * weight)
+            });
+            sum.map(|sum| sum % 10) == Some(0)
+        }
+        _ => false,
+    };
+
+    if valid { Ok(digits.to_uppercase()) } else { Err(invalid()) }
+}
+
+/// 出版日の書式を確かめる
+/// YYYY, YYYY-MM, YYYY-MM-DD, RFC 3339の日時を受け付ける
+fn check_date(date: &str) -> Result<String, failure::Error> {
+    use chrono::NaiveDate;
+
+    let date = date.trim();
+    let valid = match date.len() {
+        4 => date.parse::<u16>().is_ok(),
+        7 => NaiveDate::parse_from_str(&format!("{}-01", date), "%Y-%m-%d").is_ok(),
+        10 => NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok(),
+        _ => DateTime::parse_from_rfc3339(date).is_ok(),
+    };
+    // 年は4桁
+    let valid = valid && date.chars().take(4).all(|c| c.is_ascii_digit());
+
+    if valid {
+        Ok(date.to_string())
+    } else {
+        Err(format_err!("[ERROR] {} is not valid date (YYYY-MM-DD).", date))
+    }
+}
+
+/// 本の中身のフォルダ(content_dir)に置くpackage文書 container.xmlのrootfileになる
+const PACKAGE_FILE: &str = "package.opf";
+
+/// 本の中身を置くフォルダの名前を確かめる
+/// mimetype・META-INFと重ならない、英数字と_-.だけの1階層の名前
+fn check_content_dir(content_dir: &str) -> Result<(), failure::Error> {
+    let valid = !content_dir.is_empty()
+        && content_dir.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && content_dir.chars().any(|c| c.is_ascii_alphanumeric())
+        && !content_dir.eq_ignore_ascii_case("META-INF")
+        && !content_dir.eq_ignore_ascii_case("mimetype");
+    if valid {
+        Ok(())
+    } else {
+        Err(format_err!("[ERROR] {} can not be content directory.", content_dir))
+    }
+}
+
+/// よくある誤りの言語コードと正しいもの(国コードと取り違えやすい)
+const MISTAKEN_LANGUAGES: [(&str, &str); 7] = [
+    ("jp", "ja"), ("cn", "zh"), ("kr", "ko"), ("gr", "el"), ("dk", "da"), ("cz", "cs"), ("ua", "uk"),
+];
+
+/// 言語タグがBCP 47の形になっているか確かめる
+/// 主言語は英字2-3文字、続く部分(文字体系・地域など)は英数字1-8文字
+fn check_language(language: &str) -> Result<(), failure::Error> {
+    let mut subtags = language.split('-');
+    let primary = subtags.next().unwrap_or("");
+
+    if let Some((_, correct)) = MISTAKEN_LANGUAGES.iter().find(|(mistaken, _)| primary.eq_ignore_ascii_case(mistaken)) {
+        return Err(format_err!("[ERROR] {} is not valid language (did you mean {}?).", language, correct));
+    }
+
+    let valid = (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric()));
+    if valid {
+        Ok(())
+    } else {
+        Err(format_err!("[ERROR] {} is not valid language (BCP 47 such as \"ja\" or \"en-US\").", language))
+    }
+}
+
+/// comrak_optionで変えられるcomrakの設定
+const COMRAK_OPTIONS: [&str; 9] = ["tables", "strikethrough", "autolink", "footnotes", "tasklist", "superscript", "smart", "unsafe", "hardbreaks"];
+
+/// repubがstylesフォルダに置くcss
+const RESERVED_STYLES: [&str; 7] = ["vertical.css", "base.css", "custom.css", "code.css", "fonts.css", "fixed_layout.css", "title_page.css"];
+
+/// フォントの拡張子からmedia-typeを決める
+fn font_media_type(path: &Path, overrides: &HashMap<String, String>) -> Result<String, failure::Error> {
+    let media_type = media_type_of(path, overrides);
+    let is_font = media_type.starts_with("font/")
+        || media_type == "application/vnd.ms-opentype"
+        || media_type == "application/font-woff"
+        || media_type == "application/font-sfnt";
+    if is_font {
+        Ok(media_type)
+    } else {
+        Err(format_err!("[ERROR] {:?} is not supported font.", path))
+    }
+}
+
+trait ToCTrait {
+    fn get_inner_items(&mut self) -> &mut Vec<ToCItem>;
+
+    fn get_latest(&mut self, level: u8) -> &mut ToCItem;
+
+    fn push(&mut self, toc_item: ToCItem) {
+        self.get_inner_items().push(toc_item);
+    }
+}
+
+/// 目次の要素のひとつ
+#[derive(Debug)]
+struct ToCItem {
+    is_dummy: bool,
+    filename: String,
+    id: Option<String>,
+    title: String,
+    level: u8,
+    inner_items: Vec<ToCItem>,
+}
+
+impl Default for ToCItem {
+    fn default() -> Self {
+        ToCItem {
+            is_dummy: true,
+            filename: String::new(),
+            id: None,
+            title: String::new(),
+            level: 1,
+            inner_items: Vec::new(),
+        }
+    }
+}
+
+impl ToCTrait for ToCItem {
+    fn get_inner_items(&mut self) -> &mut Vec<ToCItem> {
+        &mut self.inner_items
+    }
+
+    fn get_latest(&mut self, level: u8) -> &mut ToCItem {
+        if level == 1 { return self; }
+
+        let inner_items = self.get_inner_items();
+        let toc_item = if inner_items.len() == 0 {
+            // initialize
+            inner_items.push(ToCItem::default());
+            inner_items[0].borrow_mut()
+        } else {
+            inner_items.last_mut().unwrap()
+        };
+
+        toc_item.get_latest(level - 1)
+    }
+}
+
+impl ToCItem {
+    /// xhtml化
+    fn to_nav(&self, level: u8) -> String {
+        let title = if self.is_dummy {
+            String::new()
+        } else {
+            match &self.id {
+                Some(id) => {
+                    format!("<a href=\"{}.xhtml#{}\">{}</a>", escape_xml(&self.filename), escape_xml(id), escape_xml(&self.title))
+                }
+                // idがなければページの先頭へ
+                None => {
+                    format!("<a href=\"{}.xhtml\">{}</a>", escape_xml(&self.filename), escape_xml(&self.title))
+                }
+            }
+        };
+        let inners: Vec<String> =
+            self.inner_items
+                .iter()
+                .map(|a| a.to_nav(level)).collect();
+        let inners_xhtml = if inners.is_empty() {
+            String::new()
+        } else {
+            if self.level >= level {
+                format!("<ol hidden=\"hidden\">{}</ol>", inners.join(""))
+            } else {
+                format!("<ol>{}</ol>", inners.join(""))
+            }
+        };
+
+        format!("<li>\n{}\n{}\n</li>\n", &title, &inners_xhtml)
+    }
+
+    /// ncxのnavPoint要素に変換
+    /// * play_order - 直前のnavPointのplayOrder
+    fn to_ncx(&self, play_order: &mut usize) -> String {
+        // ダミーは飛ばして中身を繰り上げる
+        if self.is_dummy {
+            return self.inner_items
+                .iter()
+                .map(|a| a.to_ncx(play_order)).collect();
+        }
+
+        *play_order += 1;
+        let order = *play_order;
+        let src = match &self.id {
+            Some(id) => format!("{}.xhtml#{}", &self.filename, id),
+            None => format!("{}.xhtml", &self.filename),
+        };
+        let inners: String =
+            self.inner_items
+                .iter()
+                .map(|a| a.to_ncx(play_order)).collect();
+
+        format!("<navPoint id=\"navPoint-{0}\" playOrder=\"{0}\">\n<navLabel><text>{1}</text></navLabel>\n<content src=\"{2}\" />\n{3}</navPoint>\n",
+                order, escape_xml(&self.title), src, inners)
+    }
+
+    /// dry-run用 見出しとリンク先を階層ごとに字下げして並べる
+    fn to_plan(&self, indent: usize) -> String {
+        let mut plan = if self.is_dummy {
+            String::new()
+        } else {
+            let href = match &self.id {
+                Some(id) => format!("{}.xhtml#{}", &self.filename, id),
+                None => format!("{}.xhtml", &self.filename),
+            };
+            format!("{}{} -> {}\n", "    ".repeat(indent), &self.title, href)
+        };
+        for item in &self.inner_items {
+            plan.push_str(&item.to_plan(indent + 1));
+        }
+        plan
+    }
+
+    /// 見出しに番号を付ける 中身は"1.2"のように親の番号につなげる
+    /// ダミーは番号を取らず、中身は親の番号に続ける
+    /// * numbers - 親までの番号
+    fn number(&mut self, numbers: &[usize]) {
+        let mut count = 0;
+        for item in &mut self.inner_items {
+            if item.is_dummy {
+                item.number(numbers);
+                continue;
+            }
+            count += 1;
+            let mut item_numbers = numbers.to_vec();
+            item_numbers.push(count);
+            let label = item_numbers.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(".");
+            item.title = format!("{} {}", label, item.title);
+            item.number(&item_numbers);
+        }
+    }
+
+    /// ncxに書き出す際の階層の深さ
+    fn ncx_depth(&self) -> usize {
+        let inner_depth = self.inner_items.iter().map(|a| a.ncx_depth()).max().unwrap_or(0);
+        if self.is_dummy { inner_depth } else { inner_depth + 1 }
+    }
+}
+
+/// 目次そのもの
+#[derive(Default)]
+struct ToC {
+    inner_items: Vec<ToCItem>
+}
+
+impl ToCTrait for ToC {
+    fn get_inner_items(&mut self) -> &mut Vec<ToCItem> {
+        &mut self.inner_items
+    }
+
+    fn get_latest(&mut self, level: u8) -> &mut ToCItem {
+        let inner_items = self.get_inner_items();
+        let toc_item = if inner_items.len() == 0 {
+            // initialize
+            inner_items.push(ToCItem::default());
+            inner_items[0].borrow_mut()
+        } else {
+            inner_items.last_mut().unwrap()
+        };
+
+        toc_item.get_latest(level)
+    }
+}
+
+impl ToC {
+    /// どのページも目次から開けるよう、深い見出ししかないページは最初の見出しをdepthに上げて載せる
+    /// * depth - これより深いレベルのheaderは目次に載せない
+    fn new(toc_items: Vec<ToCItem>, depth: u8) -> Self {
+        let mut origin = ToC::default();
+
+        // depth以内の見出しがあるページ
+        let shallow: Vec<String> = toc_items.iter()
+            .filter(|item| item.level <= depth)
+            .map(|item| item.filename.clone())
+            .collect();
+        let mut promoted: Vec<String> = Vec::new();
+        for mut toc_item in toc_items {
+            if toc_item.level > depth {
+                if shallow.contains(&toc_item.filename) || promoted.contains(&toc_item.filename) {
+                    continue;
+                }
+                promoted.push(toc_item.filename.clone());
+                toc_item.level = depth;
+            }
+            let level = toc_item.level;
+            origin.push(toc_item, level);
+        }
+
+        origin
+    }
+
+    /// 章に番号を付ける 章の中の見出しは"1.2"のようにつなげる
+    fn number(&mut self, style: ChapterNumbering) {
+        let mut count = 0;
+        for item in &mut self.inner_items {
+            if item.is_dummy {
+                item.number(&[]);
+                continue;
+            }
+            count += 1;
+            item.title = format!("{} {}", style.label(count), item.title);
+            item.number(&[count]);
+        }
+    }
+
+    fn push(&mut self, toc_item: ToCItem, level: u8) {
+        if level == 1 {
+            self.inner_items.push(toc_item);
+        } else {
+            self.get_latest(level - 1).push(toc_item);
+        }
+    }
+
+    /// * stylesheets - 本文のページと同じく追加で読み込むcssのlink要素
+    /// * landmarks - 目次の後に置くlandmarksのnav要素
+    fn to_nav(&self, level: u8, stylesheets: &str, title: Option<String>, language: &str, landmarks: &str) -> String {
+        let inners: Vec<String> =
+            self.inner_items
+                .iter()
+                .map(|a| a.to_nav(level)).collect();
+        let inners_xhtml = if inners.is_empty() {
+            String::new()
+        } else {
+            inners.join("")
+        };
+        let title = escape_xml(&title.unwrap_or_default());
+        let language = escape_xml(language);
+        format!(include_str!("literals/navigation.xhtml"),
+                &language,
+                &language,
+                &title,
+                stylesheets,
+                &title,
+                &inners_xhtml,
+                landmarks)
+    }
+
+    /// EPUB2向けのtoc.ncxに変換
+    fn to_ncx(&self, id: &str, title: &str) -> String {
+        let mut play_order = 0;
+        let nav_points: String =
+            self.inner_items
+                .iter()
+                .map(|a| a.to_ncx(&mut play_order)).collect();
+        let depth = self.inner_items.iter().map(|a| a.ncx_depth()).max().unwrap_or(0);
+
+        format!(include_str!("literals/toc.ncx"),
+                escape_xml(id),
+                depth.max(1),
+                escape_xml(title),
+                &nav_points)
+    }
+}
+
+impl RepubBuilder {
+    /// 絶対パス、あるいは相対パスでソースを指定してRepubBuilderを得る
+    /// ソースは.mdファイル、あるいは.mdファイルの入ったディレクトリ
+    pub fn from_path(path: &Path) -> RepubBuilder {
+        RepubBuilder {
+            source_file: path.to_path_buf(),
+            ..RepubBuilder::default()
+        }
+    }
+
+    /// ファイルを介さずにmarkdownを渡してRepubBuilderを得る 標準入力から読む場合など
+    /// nameは章のファイル名になり、画像はカレントディレクトリからの相対pathで探す
+    pub fn from_markdown(name: &str, markdown: &str) -> Result<RepubBuilder, failure::Error> {
+        let origin = std::env::current_dir()?;
+        let source_file = origin.join(format!("{}.md", sanitize_filename(name)));
+        Ok(RepubBuilder {
+            markdown: vec![(source_file.clone(), markdown.to_string())],
+            source_file,
+            ..RepubBuilder::default()
+        })
+    }
+
+    /// メモリ上のmarkdownを章として加える 加えた順に並び、buildまでファイルには書き込まない
+    /// 章を加えるとsource_fileのファイルは読まない nameは章のファイル名になり、
+    /// 画像はsource_fileのディレクトリ(なければカレントディレクトリ)からの相対pathで探す
+    pub fn add_chapter(&mut self, name: &str, markdown: &str) -> &mut Self {
+        let dir = if self.source_file.is_dir() {
+            self.source_file.clone()
+        } else {
+            self.source_file.parent().map_or_else(PathBuf::new, Path::to_path_buf)
+        };
+        // 同じ名前の章は_2, _3...を付けて区別する
+        let stem = sanitize_filename(name);
+        let mut path = dir.join(format!("{}.md", stem));
+        let mut n = 1;
+        while self.markdown.iter().any(|(taken, _)| *taken == path) {
+            n += 1;
+            path = dir.join(format!("{}_{}.md", stem, n));
+        }
+        self.markdown.push((path, markdown.to_string()));
+        self
+    }
+
+    pub fn titled(&mut self, title: &str) -> &mut Self {
+        self.title = title.to_string();
+        self
+    }
+
+    /// 作者などを追加する "名前:役割"の形式で役割を指定できる
+    pub fn creator(&mut self, creator: &str) -> &mut Self {
+        let creator = Creator::parse(creator);
+        if !creator.name.is_empty() {
+            self.creators.push(creator);
+        }
+        self
+    }
+
+    /// 挿絵、装丁、校正などの協力者を追加する "名前:役割"の形式で役割を指定できる(省略時はctb)
+    pub fn contributor(&mut self, contributor: &str) -> &mut Self {
+        let contributor = Creator::parse_with_role(contributor, "ctb");
+        if !contributor.name.is_empty() {
+            self.contributors.push(contributor);
+        }
+        self
+    }
+
+    /// 本の主な言語 各ページのlangにもなる
+    pub fn language(&mut self, language: &str) -> &mut Self {
+        self.language = language.to_string();
+        self
+    }
+
+    /// 主な言語のほかに本で使う言語 複数可 dc:languageに加える
+    pub fn other_language(&mut self, language: &str) -> &mut Self {
+        let language = language.trim();
+        if !language.is_empty() && language != self.language && !self.languages.iter().any(|l| l == language) {
+            self.languages.push(language.to_string());
+        }
+        self
+    }
+
+    /// 言語タグがBCP 47の形か確かめる(既定で確かめる)
+    pub fn validate_language(&mut self, validate_language: bool) -> &mut Self {
+        self.validate_language = validate_language;
+        self
+    }
+
+    pub fn publisher(&mut self, publisher: &str) -> &mut Self {
+        self.publisher = Some(publisher.to_string());
+        self
+    }
+
+    /// 著作権表示 dc:rightsに書き出す
+    pub fn rights(&mut self, rights: &str) -> &mut Self {
+        self.rights = Some(rights.to_string());
+        self
+    }
+
+    /// シリーズ名 Calibreのcalibre:seriesとEPUB3のbelongs-to-collectionに書き出す
+    pub fn series(&mut self, series: &str) -> &mut Self {
+        self.series = Some(series.to_string());
+        self
+    }
+
+    /// シリーズの中の巻数("2"や"2.5") 数でなければエラー seriesがなければ書き出さない
+    pub fn series_index(&mut self, index: &str) -> Result<&mut Self, failure::Error> {
+        match index.trim().parse::<f64>() {
+            Ok(n) if n.is_finite() && n >= 0.0 => {
+                self.series_index = Some(index.trim().to_string());
+                Ok(self)
+            }
+            _ => Err(format_err!("[ERROR] series index {} is not a number.", index)),
+        }
+    }
+
+    pub fn description(&mut self, description: &str) -> &mut Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    /// ジャンルやキーワード 複数可 空のものは無視する
+    pub fn subject(&mut self, subject: &str) -> &mut Self {
+        let subject = subject.trim();
+        if !subject.is_empty() {
+            self.subjects.push(subject.to_string());
+        }
+        self
+    }
+
+    /// 更新日時を固定する 指定しなければビルドした時刻になる
+    pub fn modified(&mut self, modified: DateTime<Utc>) -> &mut Self {
+        self.modified = Some(modified);
+        self
+    }
+
+    /// 出版日 dc:dateに書き出す 指定しなければdc:dateは書かない
+    /// YYYY-MM-DDなどの書式でなければエラー
+    pub fn date(&mut self, date: &str) -> Result<&mut Self, failure::Error> {
+        self.date = Some(check_date(date)?);
+        Ok(self)
+    }
+
+    /// 各ページのxhtmlの雛形 {title}, {style_links}, {body}(と任意で{lang})を置き換える
+    /// 指定しなければ同梱のものを使う
+    pub fn template(&mut self, template: PathBuf) -> &mut Self {
+        self.template = Some(template);
+        self
+    }
+
+    /// 雛形を読み込み、必要な置き換え場所があるか確かめる
+    fn load_template(&self) -> Result<String, failure::Error> {
+        let path = match &self.template {
+            Some(path) => path,
+            // EPUB2はXHTML 1.1で書く epub:typeや<section>は使えない
+            None => return Ok(match self.epub_version {
+                EpubVersion::V2 => include_str!("literals/template2.xhtml"),
+                EpubVersion::V3 => include_str!("literals/template.xhtml"),
+            }.to_string()),
+        };
+        if !path.is_file() {
+            return Err(format_err!("[ERROR] template {:?} does not exist.", path));
+        }
+        let template = std::fs::read_to_string(path)?;
+        for placeholder in TEMPLATE_PLACEHOLDERS.iter() {
+            if !template.contains(placeholder) {
+                return Err(format_err!("[ERROR] template {:?} has no {} placeholder.", path, placeholder));
+            }
+        }
+        Ok(template)
+    }
+
+    /// 本の中身を置くフォルダの名前 指定しなければOEBPS
+    pub fn content_dir(&mut self, content_dir: &str) -> &mut Self {
+        self.content_dir = content_dir.to_string();
+        self
+    }
+
+    /// 追加するcss 複数指定したときはこの順に読み込む
+    pub fn style(&mut self, style: PathBuf) -> &mut Self {
+        self.styles.push(style);
+        self
+    }
+
+    /// markdownを通さずにそのまま入れるxhtmlのページ 複数可
+    /// positionはspineでの位置(1始まり) 指定しなければmarkdownのページの後ろ
+    pub fn append_chapter(&mut self, path: PathBuf, position: Option<usize>) -> &mut Self {
+        self.raw_chapters.push((path, position));
+        self
+    }
+
+    pub fn cover(&mut self, cover: PathBuf) -> &mut Self {
+        self.cover = Some(cover);
+        self
+    }
+
+    /// 埋め込むフォント(ttf, otf, woff2) 複数可
+    pub fn font(&mut self, font: PathBuf) -> &mut Self {
+        self.fonts.push(font);
+        self
+    }
+
+    /// 出力する.epubファイルのpath 指定しなければ"タイトル.epub"
+    pub fn output(&mut self, output: PathBuf) -> &mut Self {
+        self.output = Some(output);
+        self
+    }
+
+    /// outputを指定しないとき"タイトル.epub"を置くフォルダ なければ作る
+    pub fn output_dir(&mut self, output_dir: PathBuf) -> &mut Self {
+        self.output_dir = Some(output_dir);
+        self
+    }
+
+    /// 作業ディレクトリ(repub-{uuid})を作る場所 指定しなければOSの一時ディレクトリ
+    pub fn tmp_dir(&mut self, tmp_dir: PathBuf) -> &mut Self {
+        self.tmp_dir = Some(tmp_dir);
+        self
+    }
+
+    /// 拡張子のmedia-typeを上書きする "ext=media/type"の形式
+    pub fn media_type_override(&mut self, text: &str) -> Result<&mut Self, failure::Error> {
+        let (ext, media_type) = match text.find('=') {
+            Some(index) => (text[..index].trim().trim_start_matches('.'), text[index + 1..].trim()),
+            None => return Err(format_err!("[ERROR] {} is not ext=media/type.", text)),
+        };
+        if ext.is_empty() || !media_type.contains('/') {
+            return Err(format_err!("[ERROR] {} is not ext=media/type.", text));
+        }
+        self.media_types.insert(ext.to_lowercase(), media_type.to_string());
+        Ok(self)
+    }
+
+    /// mdファイルの文字コード("shift_jis", "euc-jp", "latin1"など) 指定しなければ推測する
+    /// 知らない名前ならエラー
+    pub fn encoding(&mut self, label: &str) -> Result<&mut Self, failure::Error> {
+        self.encoding = Some(Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| format_err!("[ERROR] {} is not known encoding.", label))?);
+        Ok(self)
+    }
+
+    /// markdownとして読む拡張子 指定しなければmd, markdown, mdown, mkd
+    pub fn extensions(&mut self, extensions: &[&str]) -> &mut Self {
+        self.extensions = extensions.iter()
+            .map(|ext| ext.trim().trim_start_matches('.').to_string())
+            .filter(|ext| !ext.is_empty())
+            .collect();
+        self
+    }
+
+    /// ディレクトリを読むときに潜るサブディレクトリの深さ 0ならサブディレクトリを読まない
+    pub fn max_depth(&mut self, max_depth: usize) -> &mut Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// 改行を<br />に変換するかどうか
+    pub fn hardbreaks(&mut self, hardbreaks: bool) -> &mut Self {
+        self.hardbreaks = hardbreaks;
+        self
+    }
+
+    /// 識別子 "urn:isbn:..."や"urn:uuid:..."のようにschemeを付けられる
+    /// schemeのないUUIDはurn:uuid:を補う urn:isbn:はisbnと同じく確かめてハイフンを除く
+    pub fn book_id(&mut self, book_id: &str) -> Result<&mut Self, failure::Error> {
+        let book_id = book_id.trim();
+        if let Some(isbn) = book_id.strip_prefix("urn:isbn:") {
+            return self.isbn(isbn);
+        }
+        self.id = Some(if !book_id.starts_with("urn:") && is_uuid(book_id) {
+            format!("urn:uuid:{}", book_id.to_lowercase())
+        } else {
+            book_id.to_string()
+        });
+        Ok(self)
+    }
+
+    /// ISBN(10桁または13桁)を識別子にする ハイフンは除く
+    pub fn isbn(&mut self, isbn: &str) -> Result<&mut Self, failure::Error> {
+        let digits = isbn_digits(isbn)?;
+        self.id = Some(format!("urn:isbn:{}", digits));
+        Ok(self)
+    }
+
+    /// 識別子を指定しないとき、タイトルと作者から毎回同じUUID(v5)を作る
+    /// falseならビルドのたびにランダムなUUID(v4)になる
+    pub fn deterministic_id(&mut self, deterministic_id: bool) -> &mut Self {
+        self.deterministic_id = deterministic_id;
+        self
+    }
+
+    /// 同じ入力から毎回同じ.epubを作る
+    /// 識別子をタイトルと作者から作り、更新日時を指定しなければ1980-01-01T00:00:00Zに固定する
+    pub fn deterministic(&mut self, deterministic: bool) -> &mut Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// 更新日時 zip内のファイルの日時にも使う
+    fn modified_or_default(&self) -> DateTime<Utc> {
+        use chrono::TimeZone;
+
+        match self.modified {
+            Some(modified) => modified,
+            // zipで扱える最も古い日時
+            None if self.deterministic => Utc.ymd(1980, 1, 1).and_hms(0, 0, 0),
+            None => Utc::now(),
+        }
+    }
+
+    /// 指定された識別子 なければurn:uuid:を作る
+    fn book_id_or_default(&self) -> String {
+        if let Some(id) = &self.id {
+            return id.clone();
+        }
+
+        let uuid = if self.deterministic_id || self.deterministic {
+            let mut name = self.title.clone();
+            for creator in &self.creators {
+                name.push('\n');
+                name.push_str(&creator.name);
+            }
+            Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes())
+        } else {
+            Uuid::new_v4()
+        };
+        format!("urn:uuid:{}", uuid)
+    }
+
+    /// 縦書き writing_mode(WritingMode::VerticalRl)と同じ
+    pub fn vertical(&mut self, vertical: bool) -> &mut Self {
+        self.writing_mode = if vertical { WritingMode::VerticalRl } else { WritingMode::HorizontalTb };
+        self
+    }
+
+    /// 書字方向
+    pub fn writing_mode(&mut self, writing_mode: WritingMode) -> &mut Self {
+        self.writing_mode = writing_mode;
+        self
+    }
+
+    /// 各ページのviewport 指定しなければ書かない
+    pub fn viewport(&mut self, viewport: Viewport) -> &mut Self {
+        self.viewport = Some(viewport);
+        self
+    }
+
+    /// 固定レイアウト(pre-paginated)にする 固定サイズのviewportが必要
+    pub fn fixed_layout(&mut self, fixed_layout: bool) -> &mut Self {
+        self.fixed_layout = fixed_layout;
+        self
+    }
+
+    /// 目次のページを読む順のどこに置くか 指定しなければ最初(表紙の次)
+    /// Omittedでもnavigation.xhtmlはmanifestに残る
+    pub fn toc_in_spine(&mut self, toc_in_spine: TocPosition) -> &mut Self {
+        self.toc_in_spine = toc_in_spine;
+        self
+    }
+
+    /// 目次のページを読む順の最初(表紙の次)に置くか falseなら本文の後ろに置く
+    pub fn toc_first(&mut self, toc_first: bool) -> &mut Self {
+        self.toc_in_spine = if toc_first { TocPosition::First } else { TocPosition::Last };
+        self
+    }
+
+    /// 1章だけの本では目次のページを読む順に入れない(目次としてはリーダーから使える)
+    pub fn flatten_single_chapter(&mut self, flatten_single_chapter: bool) -> &mut Self {
+        self.flatten_single_chapter = flatten_single_chapter;
+        self
+    }
+
+    /// 代替テキスト(alt)のない画像を警告ではなくエラーにする
+    pub fn strict_a11y(&mut self, strict_a11y: bool) -> &mut Self {
+        self.strict_a11y = strict_a11y;
+        self
+    }
+
+    /// ページ送りの方向 指定しなければ書字方向に合わせる
+    /// vertical-rlは右綴じ、vertical-lrは左綴じ、横書きはリーダーに任せる
+    pub fn direction(&mut self, direction: Direction) -> &mut Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    fn direction_or_default(&self) -> Direction {
+        match self.direction {
+            Some(direction) => direction,
+            None => match self.writing_mode {
+                WritingMode::VerticalRl => Direction::Rtl,
+                WritingMode::VerticalLr => Direction::Ltr,
+                WritingMode::HorizontalTb => Direction::Default,
+            },
+        }
+    }
+
+    /// order.txtに載っていないファイルを変換しない
+    pub fn omit_unlisted(&mut self, omit_unlisted: bool) -> &mut Self {
+        self.omit_unlisted = omit_unlisted;
+        self
+    }
+
+    /// 目次に表示するHeaderの最低レベル(1~6)
+    pub fn toc_level(&mut self, toc_level: u8) -> &mut Self {
+        self.toc_level = toc_level.saturating_sub(1);
+        self
+    }
+
+    /// 目次に載せるHeaderの最大レベル(1~6) これより深いHeaderは目次から除く
+    /// toc_levelより深いHeaderは目次に載るが、折りたたまれる
+    pub fn toc_depth(&mut self, toc_depth: u8) -> &mut Self {
+        self.toc_depth = toc_depth;
+        self
+    }
+
+    /// 目次の章に番号を付ける 章の中の見出しは"1.2"のように算用数字をつなげる
+    pub fn number_chapters(&mut self, style: ChapterNumbering) -> &mut Self {
+        self.chapter_numbering = Some(style);
+        self
+    }
+
+    /// 目次を見出しから作るか、章のファイルごとに作るか
+    /// Filesではfront matterのタイトル(なければファイル名)を1ファイル1項目で載せる
+    pub fn toc_from(&mut self, toc_from: TocSource) -> &mut Self {
+        self.toc_from = toc_from;
+        self
+    }
+
+    /// タイトル・作者・出版日から扉(title.xhtml)を作り、表紙の次に置く
+    /// 扉を自分で用意するときは指定しない
+    pub fn title_page(&mut self, title_page: bool) -> &mut Self {
+        self.title_page = title_page;
+        self
+    }
+
+    /// 目次のページに表紙・目次・本文の始まりを示すlandmarksを加える EPUB3のみ
+    pub fn landmarks(&mut self, landmarks: bool) -> &mut Self {
+        self.landmarks = landmarks;
+        self
+    }
+
+    /// 生成した.epubをepubcheckで検査し、エラーがあればビルドを失敗にする
+    /// epubcheckがなければ警告だけ出す
+    pub fn check(&mut self, check: bool) -> &mut Self {
+        self.check = check;
+        self
+    }
+
+    /// 引用符やダッシュを約物に置き換える("..."->“...”, --->—) コードの中はそのまま
+    pub fn smart(&mut self, smart: bool) -> &mut Self {
+        self.smart = smart;
+        self
+    }
+
+    /// markdown中のHTMLをエスケープせずそのまま書き出す 入力を信頼するときだけ使う
+    /// 書き出したページがXMLとして整形式でなければエラーにする
+    pub fn allow_html(&mut self, allow_html: bool) -> &mut Self {
+        self.allow_html = allow_html;
+        self
+    }
+
+    /// comrakの設定を"key=value"(valueはtrueかfalse 省略するとtrue)で変える
+    /// keyはCOMRAK_OPTIONSのどれか smart, unsafe, hardbreaksはそれぞれsmart, allow_html, hardbreaksと同じ
+    pub fn comrak_option(&mut self, option: &str) -> Result<&mut Self, failure::Error> {
+        let (key, value) = match option.find('=') {
+            Some(index) => (option[..index].trim(), option[index + 1..].trim()),
+            None => (option.trim(), "true"),
+        };
+        let value = match value {
+            "true" => true,
+            "false" => false,
+            _ => return Err(format_err!("[ERROR] {} is not boolean (true, false).", value)),
+        };
+        match key {
+            "smart" => self.smart = value,
+            "unsafe" => self.allow_html = value,
+            "hardbreaks" => self.hardbreaks = value,
+            _ => match COMRAK_OPTIONS.iter().find(|name| **name == key) {
+                Some(name) => { self.comrak_extensions.insert(name, value); }
+                None => {
+                    return Err(format_err!("[ERROR] {} is not comrak option ({}).", key, COMRAK_OPTIONS.join(", ")));
+                }
+            },
+        }
+        Ok(self)
+    }
+
+    /// $...$と$$...$$のLaTeXをMathMLに変換する
+    pub fn math(&mut self, math: bool) -> &mut Self {
+        self.math = math;
+        self
+    }
+
+    /// mdファイルをこのレベルの見出しごとに別のxhtmlに分ける(1~6)
+    pub fn split_level(&mut self, level: u8) -> &mut Self {
+        self.split_level = Some(level);
+        self
+    }
+
+    /// 短い文書向けに、すべての章を一つのxhtml(content.xhtml)にまとめる
+    /// 目次は各章の見出しを指す split_levelとは同時に使えない
+    pub fn merge(&mut self, merge: bool) -> &mut Self {
+        self.merge = merge;
+        self
+    }
+
+    /// 作業ディレクトリも.epubも作らず、読む順・manifest・目次を組み立てるだけにする 結果はplan()で得る
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    fn staging(&self) -> Staging {
+        Staging { dry_run: self.dry_run }
+    }
+
+    /// 変換の進み具合を標準エラー出力に表示しない
+    pub fn quiet(&mut self, quiet: bool) -> &mut Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// 目次の見出し 指定しなければ言語に合わせる
+    pub fn toc_title(&mut self, toc_title: &str) -> &mut Self {
+        self.toc_title = Some(toc_title.to_string());
+        self
+    }
+
+    /// コードブロックの色付けに使うテーマ 指定しなければInspiredGitHub
+    pub fn highlight_theme(&mut self, highlight_theme: &str) -> &mut Self {
+        self.highlight_theme = highlight_theme.to_string();
+        self
+    }
+
+    /// 出力するEPUBのバージョン 指定しなければEPUB3
+    /// EPUB2ではページをXHTML 1.1で書き出す(epub:typeや<section>を使わない)
+    pub fn epub_version(&mut self, epub_version: EpubVersion) -> &mut Self {
+        self.epub_version = epub_version;
+        self
+    }
+
+    /// 変換後に本文の語数・文字数を数える 結果はtext_stats()で得る
+    pub fn stats(&mut self, stats: bool) -> &mut Self {
+        self.stats = stats;
+        self
+    }
+
+    /// 変換できない章があっても止めず、飛ばして残りで本を作る 飛ばした章はfailed_files()で得る
+    pub fn continue_on_error(&mut self, continue_on_error: bool) -> &mut Self {
+        self.continue_on_error = continue_on_error;
+        self
+    }
+
+    /// 一時ファイルを消去せずそのままにする
+    pub fn save_tmp_files(&mut self, save_tmp_files: bool) -> &mut Self {
+        self.save_tmp_files = save_tmp_files;
+        self
+    }
+
+    /// タイトルが設定されているか確認する
+    pub fn require_title(&self) -> Result<&str, failure::Error> {
+        if self.title.trim().is_empty() {
+            return Err(format_err!("[ERROR] title is not set."));
+        }
+        Ok(&self.title)
+    }
+
+    /// 作者が一人以上設定されているか
+    pub fn has_creator(&self) -> bool {
+        !self.creators.is_empty()
+    }
+
+    /// 先頭のファイルのfront matterで、設定されていないタイトル・作者・出版日を埋める
+    pub fn read_front_matter(&mut self) -> Result<&mut Self, failure::Error> {
+        let first = match self.source_files()?.into_iter().next() {
+            Some(first) => first,
+            None => return Ok(self),
+        };
+        let md = self.read_source(&first)?;
+        let (front_matter, _) = FrontMatter::split(&md, &first)?;
+
+        if self.title.trim().is_empty() {
+            if let Some(title) = &front_matter.title {
+                self.titled(title);
+            }
+        }
+        if self.creators.is_empty() {
+            for author in &front_matter.authors {
+                self.creator(author);
+            }
+        }
+        if self.date.is_none() {
+            if let Some(date) = &front_matter.date {
+                self.date(date)
+                    .map_err(|_| format_err!("[ERROR] date {} in {:?} is not valid date (YYYY-MM-DD).", date, &first))?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// mdファイルの中身 メモリ上のmarkdownがあればそれを返す
+    fn read_source(&self, path: &Path) -> Result<String, failure::Error> {
+        match self.markdown.iter().find(|(source, _)| source == path) {
+            Some((_, markdown)) => Ok(markdown.clone()),
+            None => read_markdown(path, self.encoding),
+        }
+    }
+
+    /// 変換するmdファイル 存在しなければ空
+    /// ディレクトリにorder.txtがあれば、その順に並べる
+    fn source_files(&self) -> Result<Vec<PathBuf>, failure::Error> {
+        if !self.markdown.is_empty() {
+            return Ok(self.markdown.iter().map(|(path, _)| path.clone()).collect());
+        } else if self.source_file.is_file() {
+            return Ok(vec![self.source_file.clone()]);
+        } else if !self.source_file.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let paths = markdown_files(&self.source_file, self.max_depth, &self.extensions)?;
+        let order_path = self.source_file.join("order.txt");
+        if !order_path.is_file() {
+            return Ok(paths);
+        }
+
+        // 1行に1ファイル ディレクトリからの相対path 空行と#で始まる行は飛ばす
+        let order = std::fs::read_to_string(&order_path)?;
+        let mut ordered = Vec::new();
+        for line in order.lines().map(|line| line.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let path = self.source_file.join(line);
+            if !path.is_file() {
+                return Err(format_err!("[ERROR] {:?} listed in {:?} does not exist.", &path, &order_path));
+            }
+            ordered.push(path);
+        }
+
+        // 載っていないファイルは名前順で後ろに付け足す
+        if !self.omit_unlisted {
+            let unlisted: Vec<PathBuf> = paths.into_iter()
+                .filter(|path| !ordered.contains(path))
+                .collect();
+            ordered.extend(unlisted);
+        }
+        Ok(ordered)
+    }
+
+    /// 言語が設定されているか確認する
+    pub fn require_language(&self) -> Result<&str, failure::Error> {
+        if self.language.trim().is_empty() {
+            return Err(format_err!("[ERROR] language is not set."));
+        }
+        Ok(&self.language)
+    }
+
+    /// 目次の見出し
+    fn toc_title_or_default(&self) -> String {
+        if let Some(toc_title) = &self.toc_title {
+            return toc_title.clone();
+        }
+
+        // "en-US"などは主言語で判断する
+        let primary = self.language.split(&['-', '_'][..]).next().unwrap_or("").to_lowercase();
+        match primary.as_str() {
+            "ja" => "目次",
+            "zh" => "目录",
+            "ko" => "목차",
+            "fr" => "Sommaire",
+            "de" => "Inhalt",
+            "es" => "Índice",
+            "it" => "Indice",
+            _ => "Contents",
+        }.to_string()
+    }
+
+    /// markdownの変換設定
+    fn comrak_options(&self) -> ComrakOptions {
+        let extension = |name: &str, default: bool| self.comrak_extensions.get(name).copied().unwrap_or(default);
+        ComrakOptions {
+            ext_header_ids: Some("header-".to_string()),
+            ext_table: extension("tables", true),
+            ext_footnotes: extension("footnotes", true),
+            ext_strikethrough: extension("strikethrough", true),
+            ext_tasklist: extension("tasklist", true),
+            ext_autolink: extension("autolink", true),
+            ext_superscript: extension("superscript", false),
+            hardbreaks: self.hardbreaks,
+            smart: self.smart,
+            unsafe_: self.allow_html,
+            ..ComrakOptions::default()
+        }
+    }
+
+    /// mimetypeファイルを配置する
+    fn add_mimetype(&mut self, dir_path: &PathBuf) -> Result<(), failure::Error> {
+        // pathを作成
+        let mimetype_path = dir_path.join("mimetype");
+        // 書き込み
+        self.staging().write(&mimetype_path, include_str!("literals/mimetype").as_bytes())?;
+
+        self.tmp_files.mimetype = Some(mimetype_path);
+
+        Ok(())
+    }
+
+    /// META-INFフォルダを配置する
+    fn add_meta_inf(&mut self, dir_path: &PathBuf) -> Result<(), failure::Error> {
+        // META-INFフォルダのpathを作成
+        let meta_inf = dir_path.join("META-INF");
+        // フォルダを作成
+        self.staging().create_dir(&meta_inf)?;
+
+        // container.xmlを作成 package.opfを実際に置く場所を指す
+        let package_path = format!("{}/{}", self.content_dir, PACKAGE_FILE);
+        self.staging().write(&meta_inf.join("container.xml"),
+                             format!(include_str!("literals/container.xml"), escape_xml(&package_path)).as_bytes())?;
+
+        self.tmp_files.meta_inf = Some(meta_inf);
+
+        Ok(())
+    }
+
+    /// OEBPSフォルダを設置する
+    /// * return - PathBuf of custom.css
+    fn add_oebps(&mut self, dir_path: &PathBuf) -> Result<PathBuf, failure::Error> {
+        // OEBPSフォルダ設置
+        let oebps_path = dir_path.join(&self.content_dir);
+        self.staging().create_dir(&oebps_path)?;
+
+        // スタイルフォルダ設置
+        let styles = oebps_path.join("styles");
+        self.staging().create_dir(&styles)?;
+
+        // 縦書きスタイル
+        self.staging().write(&styles.join("vertical.css"),
+                             format!(include_str!("literals/vertical.css"), self.writing_mode.as_css()).as_bytes())?;
+
+        // 表などの基本スタイル
+        self.staging().write(&styles.join("base.css"), include_str!("literals/base.css").as_bytes())?;
+
+        // custom style
+        let custom_css_path = styles.join("custom.css");
+        self.staging().write(&custom_css_path, b"")?;
+
+        self.tmp_files.oebps = Some(oebps_path);
+        Ok(custom_css_path)
+    }
+
+    /// タイトル・作者・出版日を載せた扉を作り、専用のcssとともにmanifestに加える
+    fn add_title_page(&self, oebps_path: &Path, items: &mut Items) -> Result<(), failure::Error> {
+        let creators: String = self.creators.iter()
+            .map(|creator| format!("<p class=\"creator\">{}</p>\n", escape_xml(&creator.name)))
+            .collect();
+        let date = self.date.as_ref()
+            .map(|date| format!("<p class=\"date\">{}</p>\n", escape_xml(date)))
+            .unwrap_or_default();
+        let template = match self.epub_version {
+            EpubVersion::V2 => include_str!("literals/title_page2.xhtml"),
+            EpubVersion::V3 => include_str!("literals/title_page.xhtml"),
+        };
+        let page = fill_template(template, &[
+            ("lang", &escape_xml(&self.language)),
+            ("viewport", &self.viewport.map(Viewport::as_meta).unwrap_or_default()),
+            ("title", &escape_xml(&self.title)),
+            ("creators", &creators),
+            ("date", &date),
+        ]);
+        self.staging().write(&oebps_path.join("title.xhtml"), page.as_bytes())?;
+
+        self.staging().write(&oebps_path.join("styles").join("title_page.css"), include_str!("literals/title_page.css").as_bytes())?;
+
+        items.items.push(Item {
+            id: Some(String::from("titlepage")),
+            href: String::from("title.xhtml"),
+            epub_type: Some(String::from("titlepage")),
+            ..Item::default()
+        });
+        items.items.push(Item {
+            id: Some(String::from("title_page_css")),
+            href: String::from("styles/title_page.css"),
+            media_type: String::from("text/css"),
+            ..Item::default()
+        });
+        Ok(())
+    }
+
+    /// 表紙画像をimagesフォルダにコピーし、表紙ページを作成する
+    /// * return - 表紙画像のmanifest id
+    fn add_cover(&self, cover: &Path, oebps_path: &Path, items: &mut Items) -> Result<String, failure::Error> {
+        let media_type = image_media_type(cover, &self.media_types)?;
+
+        // imagesフォルダ設置
+        let images = oebps_path.join("images");
+        self.staging().create_dir(&images)?;
+
+        // 表紙画像をコピー
+        let mut image_path = PathBuf::from("cover");
+        if let Some(ext) = cover.extension() {
+            image_path.set_extension(ext);
+        }
+        self.staging().copy(cover, &images.join(&image_path))?;
+        let href = format!("images/{}", image_path.to_string_lossy());
+
+        // 表紙ページ
+        let viewport = self.viewport.map(Viewport::as_meta).unwrap_or_default();
+        let page = match self.epub_version {
+            EpubVersion::V2 => format!(include_str!("literals/cover2.xhtml"), viewport, escape_xml(&self.title), &href, escape_xml(&self.title)),
+            EpubVersion::V3 => format!(include_str!("literals/cover.xhtml"), viewport, escape_xml(&self.title), &href, escape_xml(&self.title)),
+        };
+        self.staging().write(&oebps_path.join("cover.xhtml"), page.as_bytes())?;
+
+        let id = String::from("cover_image");
+        items.items.push(Item {
+            id: Some(String::from("cover")),
+            href: String::from("cover.xhtml"),
+            ..Item::default()
+        });
+        items.items.push(Item {
+            id: Some(id.clone()),
+            href,
+            media_type,
+            properties: Some(String::from("cover-image")),
+            ..Item::default()
+        });
+
+        Ok(id)
+    }
+
+    /// 指定されたcssをstylesフォルダにコピーする
+    /// 1つならcustom.cssに、複数なら元の名前で置き、manifestに加える
+    /// * return - 各ページのheadに加えるlink要素 縦書きのcssも含む
+    fn add_styles(&self, custom_css_path: &Path, items: &mut Items) -> Result<String, failure::Error> {
+        let mut links = String::new();
+        if self.writing_mode != WritingMode::HorizontalTb {
+            links.push_str("<link type=\"text/css\" rel=\"stylesheet\" href=\"styles/vertical.css\" />\n");
+        }
+
+        // 固定レイアウトでは画像だけのページを余白なしで全面に表示する
+        if self.fixed_layout {
+            let styles = custom_css_path.parent().unwrap();
+            self.staging().write(&styles.join("fixed_layout.css"), include_str!("literals/fixed_layout.css").as_bytes())?;
+            links.push_str("<link type=\"text/css\" rel=\"stylesheet\" href=\"styles/fixed_layout.css\" />\n");
+            items.items.push(Item {
+                id: Some(String::from("fixed_layout_css")),
+                href: String::from("styles/fixed_layout.css"),
+                media_type: String::from("text/css"),
+                ..Item::default()
+            });
+        }
+
+        // cssから参照されるファイルのpathと、そのhref 複数のcssで同じものは1つだけ置く
+        let mut assets = HashMap::new();
+        match self.styles.as_slice() {
+            [] => {}
+            [path] => {
+                self.copy_style(path, custom_css_path, items, &mut assets)?;
+            }
+            paths => {
+                let styles = custom_css_path.parent().unwrap();
+                for (i, path) in paths.iter().enumerate() {
+                    // repubが置くcssと重ならない名前にする
+                    let stem = file_stem_of(path)?;
+                    let mut file_name = format!("{}.css", stem);
+                    let mut n = 1;
+                    let taken = |file_name: &str| {
+                        let href = format!("styles/{}", file_name);
+                        RESERVED_STYLES.contains(&file_name) || items.items.iter().any(|item| item.href == href)
+                    };
+                    while taken(&file_name) {
+                        n += 1;
+                        file_name = format!("{}_{}.css", stem, n);
+                    }
+                    self.copy_style(path, &styles.join(&file_name), items, &mut assets)?;
+
+                    let href = format!("styles/{}", &file_name);
+                    links.push_str(&format!("<link type=\"text/css\" rel=\"stylesheet\" href=\"{}\" />\n", &href));
+                    items.items.push(Item {
+                        id: Some(format!("style_{}", i)),
+                        href,
+                        media_type: String::from("text/css"),
+                        ..Item::default()
+                    });
+                }
+            }
+        }
+
+        Ok(links)
+    }
+
+    /// cssをstylesフォルダに書き出す
+    /// url()が指すローカルのファイル(背景画像など)も同じフォルダにコピーし、参照先を書き換えてmanifestに加える
+    /// データURIと外部のURLはそのまま
+    fn copy_style(&self, css_path: &Path, to: &Path, items: &mut Items, assets: &mut HashMap<PathBuf, String>) -> Result<(), failure::Error> {
+        let css = std::fs::read_to_string(css_path)
+            .map_err(|e| format_err!("[ERROR] failed to read {:?} : {}", css_path, e))?;
+        let css_dir = css_path.parent().unwrap_or_else(|| Path::new("."));
+        let styles = to.parent().unwrap();
+
+        let mut copied = String::with_capacity(css.len());
+        let mut rest = css.as_str();
+        while let Some(start) = rest.find("url(") {
+            let (head, tail) = rest.split_at(start + "url(".len());
+            copied.push_str(head);
+            let end = match tail.find(')') {
+                Some(end) => end,
+                None => {
+                    rest = tail;
+                    break;
+                }
+            };
+            let (url, after) = tail.split_at(end);
+            rest = after;
+
+            let target = url.trim().trim_matches(|c| c == '"' || c == '\'');
+            let external = target.is_empty() || target.starts_with('#') || target.starts_with("data:")
+                || target.starts_with("http://") || target.starts_with("https://") || target.starts_with("//");
+            if external {
+                copied.push_str(url);
+                continue;
+            }
+            // フォントの#idや?以降は残す
+            let (target, suffix) = target.split_at(target.find(&['#', '?'][..]).unwrap_or(target.len()));
+            let asset_path = css_dir.join(percent_decode(target));
+            if !asset_path.is_file() {
+                warn!("{}: {} が見つかりません", css_path.display(), target);
+                copied.push_str(url);
+                continue;
+            }
+            let asset_path = asset_path.canonicalize()?;
+
+            let href = match assets.get(&asset_path) {
+                Some(href) => href.clone(),
+                None => {
+                    // 名前が被ったら番号をつける
+                    let stem = file_stem_of(&asset_path)?.replace(' ', "_");
+                    let ext = asset_path.extension().map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()));
+                    let mut name = format!("{}{}", stem, ext);
+                    let mut n = 1;
+                    let taken = |name: &str| {
+                        let href = format!("styles/{}", name);
+                        RESERVED_STYLES.contains(&name) || items.items.iter().any(|item| item.href == href)
+                    };
+                    while taken(&name) {
+                        n += 1;
+                        name = format!("{}_{}{}", stem, n, ext);
+                    }
+                    self.staging().copy(&asset_path, &styles.join(&name))?;
+                    items.items.push(Item {
+                        id: Some(format!("style_asset_{}", assets.len())),
+                        href: format!("styles/{}", name),
+                        media_type: media_type_of(&asset_path, &self.media_types),
+                        ..Item::default()
+                    });
+                    assets.insert(asset_path, name.clone());
+                    name
+                }
+            };
+            copied.push_str(&format!("\"{}{}\"", href, suffix));
+        }
+        copied.push_str(rest);
+
+        self.staging().write(to, copied.as_bytes())?;
+        Ok(())
+    }
+
+    /// フォントをfontsフォルダにコピーし、fonts.cssに@font-faceを書く
+    /// font-familyはファイル名(拡張子なし)
+    fn add_fonts(&self, oebps_path: &Path, items: &mut Items) -> Result<(), failure::Error> {
+        let mut font_faces = String::new();
+        if !self.fonts.is_empty() {
+            let fonts = oebps_path.join("fonts");
+            self.staging().create_dir(&fonts)?;
+
+            for (i, font) in self.fonts.iter().enumerate() {
+                let media_type = font_media_type(font, &self.media_types)?;
+                let file_name = file_name_of(font)?;
+                self.staging().copy(font, &fonts.join(file_name))?;
+                items.items.push(Item {
+                    id: Some(format!("font_{}", i)),
+                    href: format!("fonts/{}", file_name),
+                    media_type,
+                    ..Item::default()
+                });
+
+                let family = file_stem_of(font)?;
+                font_faces.push_str(&format!("@font-face {{\n    font-family: \"{}\";\n    src: url(\"../fonts/{}\");\n}}\n",
+                                             family, &file_name));
+            }
+        }
+
+        // フォントがなくてもfonts.cssは置く
+        self.staging().write(&oebps_path.join("styles").join("fonts.css"), font_faces.as_bytes())?;
+        Ok(())
+    }
+
+    /// .epubファイルを生成する
+    /// 生成に失敗したようなら、unzippedなゴミを片付ける
+    /// * return - 生成した.epubファイルのpath
+    pub fn build(&mut self) -> Result<PathBuf, failure::Error> {
+        let res = match self.build_core() {
+            // failed
+            Err(e) => {
+                Err(e)
+            }
+            // succeeded
+            Ok(ok) => {
+                Ok(ok)
+            }
+        };
+
+        if !self.save_tmp_files {
+            // ファイル削除
+            self.remove_tmp_files();
+        }
+
+        res
+    }
+
+    /// 表紙・目次・本文の始まりを示すlandmarksのnav要素
+    fn landmarks_nav(&self, items: &Items) -> String {
+        let mut landmarks = Vec::new();
+        let order = items.reading_order();
+        let pages = order.iter().map(|i| &items.items[*i]);
+
+        if let Some(cover) = pages.clone().find(|item| item.id.as_deref() == Some("cover")) {
+            landmarks.push(("cover", escape_xml(&cover.href), String::from("Cover")));
+        }
+        if let Some(title_page) = pages.clone().find(|item| item.id.as_deref() == Some("titlepage")) {
+            landmarks.push(("titlepage", escape_xml(&title_page.href), String::from("Title Page")));
+        }
+        landmarks.push(("toc", String::from("navigation.xhtml"), escape_xml(&self.toc_title_or_default())));
+        // 本文の始まり 章などのページがなければ、表紙以外でページ送りで読む最初のページ
+        let is_body = |item: &&Item| match item.epub_type.as_deref() {
+            Some("bodymatter") | Some("chapter") | Some("part") => item.linear,
+            _ => false,
+        };
+        let body = pages.clone().find(is_body)
+            .or_else(|| pages.clone().find(|item| item.id.is_none() && item.linear));
+        if let Some(body) = body {
+            landmarks.push(("bodymatter", escape_xml(&body.href), String::from("Start")));
+        }
+
+        let landmarks: String = landmarks.iter()
+            .map(|(epub_type, href, title)| format!("<li><a epub:type=\"{}\" href=\"{}\">{}</a></li>\n", epub_type, href, title))
+            .collect();
+        format!("<nav epub:type=\"landmarks\" hidden=\"hidden\">\n<ol>\n{}</ol>\n</nav>\n", landmarks)
+    }
+
+    /// continue_on_errorで飛ばした章のpathとエラー
+    pub fn failed_files(&self) -> &[(PathBuf, String)] {
+        &self.failed_files
+    }
+
+    /// dry_runで変換した場合、読む順(spine)・manifest・目次の一覧
+    pub fn plan(&self) -> Option<&str> {
+        self.plan.as_deref()
+    }
+
+    /// statsを指定して変換できた場合、その本文の語数・文字数
+    pub fn text_stats(&self) -> Option<TextStats> {
+        self.text_stats
+    }
+
+    /// 一時ファイルを残した場合、その作業ディレクトリ
+    pub fn saved_tmp_dir(&self) -> Option<&Path> {
+        if self.save_tmp_files {
+            self.tmp_files.dir.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// 一時ファイルを削除する
+    fn remove_tmp_files(&self) {
+        // pathを変数に代入
+        // 存在すれば作業ディレクトリごと削除
+        // エラーを拾ったときにもゴミ掃除をしたいので、作業ディレクトリを作る前のこともありうる
+        if let Some(dir) = &self.tmp_files.dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+
+    /// 1つのページの目次の項目 toc_fromがFilesならページの先頭を指す1項目にまとめる
+    /// 見出しで分けたページは、分けた見出しを章のタイトルにする
+    fn toc_items_of(&self, source_path: &Path, title: Option<String>, file_toc_items: Vec<ToCItem>) -> Result<Vec<ToCItem>, failure::Error> {
+        if self.toc_from == TocSource::Headings {
+            return Ok(file_toc_items);
+        }
+        let first = match file_toc_items.into_iter().next() {
+            Some(first) => first,
+            None => return Ok(Vec::new()),
+        };
+        let title = match title {
+            Some(title) => title,
+            None if self.split_level.is_some() => first.title,
+            None => humanize_file_stem(file_stem_of(source_path)?),
+        };
+        Ok(vec![ToCItem {
+            is_dummy: false,
+            filename: first.filename,
+            title,
+            ..ToCItem::default()
+        }])
+    }
+
+    /// .epubファイルを生成する
+    fn build_core(&mut self) -> Result<PathBuf, failure::Error> {
+        let souce_file_path = self.source_file.clone();
+        // 作業ディレクトリ カレントディレクトリを汚さないよう一時ディレクトリの下に毎回新しく作る
+        let tmp_root = self.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+        let dir_path = tmp_root.join(format!("repub-{}", Uuid::new_v4()));
+
+        // 必須の項目 指定がなければfront matterから
+        self.read_front_matter()?;
+        self.require_title()?;
+        self.require_language()?;
+        if self.validate_language {
+            for language in std::iter::once(&self.language).chain(&self.languages) {
+                check_language(language)?;
+            }
+        }
+
+        // 存在しないpath
+        if self.markdown.is_empty() && !souce_file_path.exists() {
+            return Err(format_err!("[ERROR] {:?} does not exist.", &souce_file_path));
+        }
+
+        // markdownファイルorディレクトリではない 拡張子のないファイルはmarkdownとして読む
+        if self.markdown.is_empty() && souce_file_path.is_file()
+            && souce_file_path.extension().is_some() && !has_extension(&souce_file_path, &self.extensions) {
+            return Err(format_err!("[ERROR] {:?} is not Markdown file ({}).", &souce_file_path, self.extensions.join(", ")));
+        }
+
+        // 変換するファイルがない 空のspineの.epubは作らない
+        if self.source_files()?.is_empty() {
+            return Err(format_err!("[ERROR] no Markdown files found in {:?}.", &souce_file_path));
+        }
+
+        // 表紙画像は何も書き込む前に確認する
+        if let Some(cover) = &self.cover {
+            if !cover.is_file() {
+                return Err(format_err!("[ERROR] cover image {:?} does not exist.", cover));
+            }
+            image_media_type(cover, &self.media_types)?;
+        }
+
+        // cssとフォントも同様
+        for style in &self.styles {
+            if !style.is_file() {
+                return Err(format_err!("[ERROR] css {:?} does not exist.", style));
+            }
+        }
+        for (chapter, _) in &self.raw_chapters {
+            if !chapter.is_file() {
+                return Err(format_err!("[ERROR] xhtml {:?} does not exist.", chapter));
+            }
+        }
+        for font in &self.fonts {
+            if !font.is_file() {
+                return Err(format_err!("[ERROR] font {:?} does not exist.", font));
+            }
+            font_media_type(font, &self.media_types)?;
+        }
+
+        // 色付けのテーマと雛形も何も書き込む前に確認する
+        let highlighter = Highlighter::new(&self.highlight_theme)?;
+        let template = self.load_template()?;
+        check_content_dir(&self.content_dir)?;
+        if self.allow_html {
+            warn!("markdown中のHTMLをそのまま書き出します scriptなども消さないので、信頼できる文書にだけ使ってください");
+        }
+
+        // 固定レイアウトはEPUB3のみで、ページの大きさが要る
+        if self.fixed_layout {
+            if self.epub_version == EpubVersion::V2 {
+                return Err(format_err!("[ERROR] fixed layout is not supported in EPUB 2."));
+            }
+            match self.viewport {
+                Some(Viewport::Fixed { .. }) => {}
+                _ => return Err(format_err!("[ERROR] fixed layout needs a fixed viewport (WIDTHxHEIGHT).")),
+            }
+        }
+
+        // 分けたものをまとめ直すことになる
+        if self.merge && self.split_level.is_some() {
+            return Err(format_err!("[ERROR] merge cannot be used with split level."));
+        }
+
+        if !self.dry_run {
+            // 既存のディレクトリには書き込まない(中身を上書きしてしまうため)
+            std::fs::create_dir_all(&tmp_root)?;
+            if let Err(e) = std::fs::create_dir(&dir_path) {
+                return Err(if e.kind() == std::io::ErrorKind::AlreadyExists {
+                    format_err!("[ERROR] working directory {:?} already exists.", &dir_path)
+                } else {
+                    e.into()
+                });
+            }
+            self.tmp_files.dir = Some(dir_path.clone());
+            debug!("作業ディレクトリ: {}", dir_path.display());
+        }
+
+        // mimetypeファイル設置
+        self.add_mimetype(&dir_path)?;
+
+        // META-INFフォルダ, container.xmlを設置
+        self.add_meta_inf(&dir_path)?;
+
+        // OEBPSフォルダ, styleフォルダ, vertical.css, base.css設置
+        let custom_css_path = self.add_oebps(&dir_path)?;
+
+        let (mimetype, meta_inf, oebps_path) = match &self.tmp_files {
+            TmpFiles {
+                mimetype: Some(mimetype),
+                meta_inf: Some(meta_inf),
+                oebps: Some(oebps_path),
+                ..
+            } => {
+                (mimetype, meta_inf, oebps_path)
+            }
+            _ => {
+                return Err(format_err!("[ERROR] file error : {}:{}:{} ",file!(),line!(),column!()));
+            }
+        };
+
+        // code.cssに書き込み
+        self.staging().write(&oebps_path.join("styles").join("code.css"), highlighter.css().as_bytes())?;
+
+
+        // ファイル読み込み&変換
+        let mut items = Items::default();
+
+        // 指定されたcss
+        let stylesheets = self.add_styles(&custom_css_path, &mut items)?;
+
+        // 表紙
+        let cover_id = match &self.cover {
+            Some(cover) => Some(self.add_cover(cover, oebps_path, &mut items)?),
+            None => None,
+        };
+
+        // 扉
+        if self.title_page {
+            self.add_title_page(oebps_path, &mut items)?;
+        }
+
+        // フォント
+        self.add_fonts(oebps_path, &mut items)?;
+
+        let mut toc_items = Vec::new();
+        // 埋め込み済みの画像
+        let mut images = Images {
+            hrefs: HashMap::new(),
+            media_types: &self.media_types,
+        };
+        let viewport = self.viewport.map(Viewport::as_meta).unwrap_or_default();
+        let page_options = PageOptions {
+            comrak_options: self.comrak_options(),
+            highlighter: &highlighter,
+            template: &template,
+            stylesheets: &stylesheets,
+            language: &self.language,
+            viewport: &viewport,
+            math: self.math,
+            epub_version: self.epub_version,
+        };
+        // ファイル or ディレクトリから中身一覧を取得
+        let paths = self.source_files()?;
+        // 見出しで分けるときは、分けたものをそれぞれ一つのファイルとして扱う
+        let mut sources = Vec::with_capacity(paths.len());
+        // 変換できなかったファイル continue_on_errorなら飛ばして最後にまとめて知らせる
+        let mut failures = Vec::new();
+        for path in &paths {
+            let md = self.read_source(path)
+                .and_then(|md| expand_includes(&md, path, self.encoding, &mut Vec::new()))
+                .and_then(|md| match self.split_level {
+                    Some(level) => split_chapters(&md, path, level),
+                    None => Ok(vec![md]),
+                });
+            match md {
+                Ok(mds) => sources.extend(mds.into_iter().map(|md| (path, md))),
+                Err(e) => chapter_failed(path, e, self.continue_on_error, &mut failures)?,
+            }
+        }
+        // 語数・文字数はhtmlのタグを含まないmarkdownで数える
+        let mut text_stats = TextStats::default();
+        // 進み具合 変換したファイル数/全体
+        let progress = if self.quiet {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(sources.len() as u64)
+        };
+        progress.set_style(ProgressStyle::default_bar().template("{pos}/{len} {wide_bar} {msg}"));
+        // xhtmlへの変換は並列に行い、画像・目次などはファイルの順にまとめる
+        let rendered = sources.par_iter()
+            .map(|(path, md)| {
+                debug!("変換: {}", path.display());
+                let rendered = render(path, md, &page_options);
+                progress.inc(1);
+                rendered
+            })
+            .collect::<Vec<_>>();
+        progress.finish_and_clear();
+        let mut chapters: Vec<Chapter> = Vec::with_capacity(sources.len());
+        // front matterのタイトルから付けたファイル名 見出しで分けたページは最初のページにそろえる
+        let mut titled_stems: HashMap<&PathBuf, String> = HashMap::new();
+        for ((path, md), rendered) in sources.iter().zip(rendered) {
+            let converted = rendered.and_then(|mut rendered| {
+                // 英数字を含まないタイトルはファイル名にならないので、mdファイル名を使う
+                if let Some(title) = rendered.front_matter.title.as_ref().filter(|title| title.chars().any(|c| c.is_ascii_alphanumeric())) {
+                    titled_stems.insert(*path, title.clone());
+                }
+                rendered.stem = titled_stems.get(*path).cloned();
+                // 代替テキストのない画像は読み上げなどで内容が伝わらない
+                let missing = images_without_alt(&rendered.html)?;
+                for src in &missing {
+                    warn!("{}: 画像 {} に代替テキストがありません", path.display(), src);
+                }
+                if self.strict_a11y && !missing.is_empty() {
+                    return Err(format_err!("[ERROR] {:?} has images without alt text.", path));
+                }
+                // 生のHTMLはXHTMLとして壊れているかもしれない
+                if self.allow_html {
+                    check_well_formed(&rendered.html)
+                        .map_err(|e| format_err!("[ERROR] {:?} has malformed HTML : {}", path, e))?;
+                }
+                let title = rendered.front_matter.title.clone();
+                let mut file_toc_items = Vec::new();
+                let ids = convert(path, rendered, oebps_path, self.staging(), &mut items, &mut file_toc_items, &mut images)?;
+                toc_items.append(&mut self.toc_items_of(path, title, file_toc_items)?);
+                Ok(ids)
+            });
+            let ids = match converted {
+                Ok(ids) => ids,
+                Err(e) => {
+                    chapter_failed(path, e, self.continue_on_error, &mut failures)?;
+                    continue;
+                }
+            };
+            if let Some(item) = items.items.last() {
+                chapters.push((*path, item.href.clone(), ids));
+            }
+            if self.stats {
+                if let Ok((_, body)) = FrontMatter::split(md, path) {
+                    text_stats += TextStats::count(body);
+                }
+            }
+        }
+        if chapters.is_empty() {
+            return Err(format_err!("[ERROR] none of the Markdown files could be converted."));
+        }
+        // そのまま入れるxhtml
+        for (path, position) in &self.raw_chapters {
+            let mut file_toc_items = Vec::new();
+            append_raw_chapter(path, *position, oebps_path, self.staging(), &mut items, &mut file_toc_items)?;
+            toc_items.append(&mut self.toc_items_of(path, None, file_toc_items)?);
+        }
+        // 分けたファイルの間のリンク
+        if self.split_level.is_some() && !self.dry_run {
+            link_split_chapters(oebps_path, &chapters)?;
+        }
+        // 章を一つのファイルにまとめる
+        if self.merge {
+            merge_chapters(oebps_path, &chapters, self.staging(), &mut items, &mut toc_items, &self.title)?;
+        }
+
+        // toc.ncx
+        items.items.push(Item {
+            id: Some(String::from("ncx")),
+            href: String::from("toc.ncx"),
+            media_type: String::from("application/x-dtbncx+xml"),
+            ..Item::default()
+        });
+
+        // package.opfとtoc.ncxで同じ識別子を使う
+        let book_id = self.book_id_or_default();
+        let modified = self.modified_or_default();
+
+        // package.opf書き込み準備
+        let metadata = MetaData {
+            title: &self.title,
+            creators: &self.creators,
+            contributors: &self.contributors,
+            language: &self.language,
+            languages: &self.languages,
+            publisher: self.publisher.as_deref(),
+            rights: self.rights.as_deref(),
+            series: self.series.as_deref(),
+            series_index: self.series_index.as_deref(),
+            description: self.description.as_deref(),
+            subjects: &self.subjects,
+            id: &book_id,
+            modified,
+            date: self.date.as_deref(),
+            cover: cover_id.as_deref(),
+            fixed_layout: self.fixed_layout,
+        };
+
+        // landmarksはmanifestを渡す前に作る
+        let landmarks = if self.landmarks { self.landmarks_nav(&items) } else { String::new() };
+
+        // package.opf書き込み
+        // 1章だけの本では、目次のページは章の見出しを繰り返すだけになる
+        let chapters = items.reading_order().into_iter()
+            .filter(|i| !matches!(items.items[*i].id.as_deref(), Some("cover") | Some("titlepage")))
+            .count();
+        let navigation = if self.flatten_single_chapter && chapters == 1 {
+            TocPosition::Omitted
+        } else {
+            self.toc_in_spine
+        };
+        let package = Package { metadata, items, navigation };
+        if !self.dry_run {
+            check_manifest(oebps_path, oebps_path, &package.items.to_manifest(self.epub_version))?;
+        }
+        self.staging().write(&oebps_path.join(PACKAGE_FILE),
+                             package.to_opf(self.direction_or_default(), self.epub_version).as_bytes())?;
+
+        let mut toc = ToC::new(toc_items, self.toc_depth);
+        if let Some(style) = self.chapter_numbering {
+            toc.number(style);
+        }
+
+        // navigation.opf作成 EPUB2では作らない
+        if self.epub_version == EpubVersion::V3 {
+            self.staging().write(&oebps_path.join("navigation.xhtml"),
+                                 toc.to_nav(self.toc_level, &stylesheets, Some(self.toc_title_or_default()), &self.language, &landmarks).as_bytes())?;
+        }
+
+        // toc.ncx作成
+        self.staging().write(&oebps_path.join("toc.ncx"), toc.to_ncx(&book_id, &self.title).as_bytes())?;
+
+        self.failed_files = failures;
+
+        // dry-runでは組み立てた中身を残すだけ
+        if self.dry_run {
+            self.plan = Some(package.to_plan(&toc));
+            return Ok(self.epub_path());
+        }
+
+        // zip圧縮
+        debug!("書き出し: {}", self.epub_path().display());
+        self.make(&mimetype, &meta_inf, &oebps_path, modified)?;
+//        self.make_with_command(mimetype, meta_inf, oebps_path)?;
+
+        // epubcheck
+        if self.check {
+            check_with_epubcheck(&self.epub_path())?;
+        }
+
+        if self.stats {
+            self.text_stats = Some(text_stats);
+        }
+        Ok(self.epub_path())
+    }
+
+    /// 出力する.epubファイルのpath
+    fn epub_path(&self) -> PathBuf {
+        match &self.output {
+            Some(output) => output.clone(),
+            None => {
+                let file_name = format!("{}.epub", sanitize_filename(&self.title));
+                match &self.output_dir {
+                    Some(output_dir) => output_dir.join(file_name),
+                    None => PathBuf::from(file_name),
+                }
+            }
+        }
+    }
+
+    /// zip前のフォルダのpathから.epubを生成する
+    fn make(&self, mimetype: &Path, meta_inf: &Path, oebps: &Path, modified: DateTime<Utc>) -> ZipResult<()> {
+        let epub_path = self.epub_path();
+        // 出力先のフォルダがなければ作る
+        if let Some(parent) = epub_path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        let epub = File::create(&epub_path)?;
+
+        // 途中で失敗したら、書きかけの.epubを残さない
+        let res = write_epub(epub, mimetype, meta_inf, oebps, modified);
+        if res.is_err() {
+            let _ = std::fs::remove_file(&epub_path);
+        }
+        res
+    }
+
+    /// zip前のフォルダのpathからコマンドを用いて.epubを生成する
+    #[allow(dead_code)]
+    fn make_with_command(&self, mimetype: &PathBuf, meta_inf: &PathBuf, oebps: &PathBuf) -> Result<(), failure::Error> {
+        use std::process::Command;
+
+        if cfg!(target_os = "macos") {
+            let epubname = &self.epub_path();
+            Command::new("zip")
+                // 無圧縮(-0)で、拡張フィールドを付けない(-X)
+                .arg("-X0q")
+                .arg(epubname)
+                .arg(mimetype.to_str().unwrap())
+                .output().expect("Missed zip mimetype");
+            Command::new("zip")
+                .arg("-Xr9Dq")
+                .arg(epubname)
+                .arg(meta_inf.to_str().unwrap())
+                .output().expect("Missed zip META-INF");
+            Command::new("zip")
+                .arg("-Xr9Dq")
+                .arg(epubname)
+                .arg(oebps.to_str().unwrap())
+                .output().expect("Missed zip content directory");
+        }
+
+        Ok(())
+    }
+}
+
+use comrak::ComrakOptions;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use scraper::{Html, Selector};
+use zip::CompressionMethod;
+use zip::result::ZipResult;
+use core::borrow::BorrowMut;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{ClassedHTMLGenerator, css_for_theme};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+use yaml_rust::{Yaml, YamlLoader};
+
+/// フェンス付きコードブロックの色付け
+struct Highlighter {
+    syntax_set: SyntaxSet,
+    theme: Theme,
+}
+
+impl Highlighter {
+    /// 同梱のテーマから選ぶ
+    fn new(theme: &str) -> Result<Highlighter, failure::Error> {
+        let mut theme_set = ThemeSet::load_defaults();
+        let theme = match theme_set.themes.remove(theme) {
+            Some(theme) => theme,
+            None => {
+                let themes: Vec<&str> = theme_set.themes.keys().map(|k| k.as_str()).collect();
+                return Err(format_err!("[ERROR] {} is not bundled highlight theme. ({})", theme, themes.join(", ")));
+            }
+        };
+        Ok(Highlighter {
+            syntax_set: SyntaxSet::load_defaults_newlines(),
+            theme,
+        })
+    }
+
+    /// code.cssの中身
+    fn css(&self) -> String {
+        css_for_theme(&self.theme)
+    }
+
+    /// `<pre><code class="language-*">`を色付けする 未知の言語はそのまま
+    fn highlight(&self, html: &str) -> String {
+        const OPEN: &str = "<pre><code class=\"language-";
+        const CLOSE: &str = "</code></pre>";
+
+        let mut result = String::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = rest.find(OPEN) {
+            result.push_str(&rest[..start]);
+            let block = &rest[start..];
+            let (lang_end, code_start, code_end) = match (block[OPEN.len()..].find('"'), block.find('>'), block.find(CLOSE)) {
+                (Some(lang_end), Some(pre_end), Some(code_end)) => {
+                    match block[pre_end + 1..].find('>') {
+                        Some(code_start) => (OPEN.len() + lang_end, pre_end + 1 + code_start + 1, code_end),
+                        None => break,
+                    }
+                }
+                _ => break,
+            };
+            let lang = &block[OPEN.len()..lang_end];
+            match self.syntax_set.find_syntax_by_token(lang) {
+                Some(syntax) => {
+                    let code = unescape_html(&block[code_start..code_end]);
+                    let mut generator = ClassedHTMLGenerator::new(syntax, &self.syntax_set);
+                    for line in LinesWithEndings::from(&code) {
+                        generator.parse_html_for_line(line);
+                    }
+                    result.push_str(&format!("<pre class=\"code\"><code class=\"language-{}\">{}</code></pre>", lang, generator.finalize()));
+                }
+                None => result.push_str(&block[..code_end + CLOSE.len()]),
+            }
+            rest = &block[code_end + CLOSE.len()..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+/// mdファイルの先頭にある`---`で囲まれたYAML
+#[derive(Debug)]
+struct FrontMatter {
+    title: Option<String>,
+    authors: Vec<String>,
+    date: Option<String>,
+    spine_position: Option<usize>,
+    linear: bool,
+    /// このページの言語 なければ本の言語
+    lang: Option<String>,
+    /// bodyのepub:type(chapter, appendixなど) なければファイル名から決める
+    epub_type: Option<String>,
+}
+
+impl Default for FrontMatter {
+    fn default() -> Self {
+        FrontMatter {
+            title: None,
+            authors: Vec::new(),
+            date: None,
+            spine_position: None,
+            linear: true,
+            lang: None,
+            epub_type: None,
+        }
+    }
+}
+
+impl FrontMatter {
+    /// front matterを読み取り、残りの本文と一緒に返す
+    /// 先頭が`---`でない、または閉じていなければfront matterはないものとする
+    fn split<'a>(md: &'a str, source_path: &Path) -> Result<(FrontMatter, &'a str), failure::Error> {
+        let body = md.trim_start_matches('\u{feff}');
+        let yaml_start = match body.find('\n') {
+            Some(i) if body[..i].trim_end() == "---" => i + 1,
+            _ => return Ok((FrontMatter::default(), md)),
+        };
+
+        let mut offset = yaml_start;
+        while offset < body.len() {
+            let line_end = body[offset..].find('\n').map(|i| offset + i + 1).unwrap_or_else(|| body.len());
+            let line = body[offset..line_end].trim_end();
+            if line == "---" || line == "..." {
+                let front_matter = FrontMatter::parse(&body[yaml_start..offset], source_path)?;
+                return Ok((front_matter, &body[line_end..]));
+            }
+            offset = line_end;
+        }
+
+        Ok((FrontMatter::default(), md))
+    }
+
+    fn parse(yaml: &str, source_path: &Path) -> Result<FrontMatter, failure::Error> {
+        let docs = YamlLoader::load_from_str(yaml)
+            .map_err(|e| format_err!("[ERROR] front matter of {:?} is malformed YAML : {}", source_path, e))?;
+        let doc = match docs.into_iter().next() {
+            None => return Ok(FrontMatter::default()),
+            Some(doc @ Yaml::Hash(_)) => doc,
+            Some(_) => return Err(format_err!("[ERROR] front matter of {:?} is not key: value pairs.", source_path)),
+        };
+
+        // authorは1人なら文字列、複数なら配列
+        let authors = match &doc["author"] {
+            Yaml::Array(authors) => authors.iter().filter_map(yaml_to_string).collect(),
+            author => yaml_to_string(author).into_iter().collect(),
+        };
+
+        Ok(FrontMatter {
+            title: yaml_to_string(&doc["title"]),
+            authors,
+            date: yaml_to_string(&doc["date"]),
+            spine_position: doc["spine_position"].as_i64().filter(|p| *p >= 1).map(|p| p as usize),
+            linear: doc["linear"].as_bool().unwrap_or(true),
+            lang: yaml_to_string(&doc["lang"]),
+            epub_type: yaml_to_string(&doc["type"]),
+        })
+    }
+}
+
+/// YAMLのスカラー値を文字列として読む
+fn yaml_to_string(yaml: &Yaml) -> Option<String> {
+    match yaml {
+        Yaml::String(s) | Yaml::Real(s) => Some(s.clone()),
+        Yaml::Integer(i) => Some(i.to_string()),
+        _ => None,
+    }
+}
+
+/// comrakがエスケープした文字を元に戻す
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// domからheaderを読み取り、li要素のVecを返す
+fn toc_from_dom(dom: Html, filename: &str) -> Result<Vec<ToCItem>, failure::Error> {
+    let header_selector = match Selector::parse("h1,h2,h3,h4,h5,h6") {
+        Ok(selector) => selector,
+        Err(_) => {
+            return Err(format_err!("[ERROR] selector parse error : {}:{}:{} ",file!(),line!(),column!()));
+        }
+    };
+    let anchor_selector = match Selector::parse("a[id]") {
+        Ok(selector) => selector,
+        Err(_) => {
+            return Err(format_err!("[ERROR] selector parse error : {}:{}:{} ",file!(),line!(),column!()));
+        }
+    };
+    let headers = dom.select(&header_selector);
+
+    let mut toc_items = Vec::new();
+    for header in headers {
+        // header text
+        // 強調やリンクなどで分かれたテキストもまとめて読む
+        let title = header.text().collect::<String>().trim().to_string();
+        let level = match header.value().name() {
+            "h1" => 1,
+            "h2" => 2,
+            "h3" => 3,
+            "h4" => 4,
+            "h5" => 5,
+            "h6" => 6,
+            name => {
+                return Err(format_err!("[ERROR] <{}> in {}.xhtml is not header.", name, filename));
+            }
+        };
+
+        // headerのid なければ目次からはページの先頭へ飛ぶ
+        // comrakのバージョンによって、idはheader自身か中のa要素につく
+        let id = header.value().id()
+            .or_else(|| header.select(&anchor_selector).next().and_then(|a| a.value().id()))
+            .map(|id| id.to_string());
+
+        toc_items.push(ToCItem {
+            is_dummy: false,
+            filename: filename.to_string(),
+            id,
+            title,
+            level,
+            ..ToCItem::default()
+        });
+    }
+
+    Ok(toc_items)
+}
+
+/// %エンコードされた文字列を元に戻す
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// XMLとして整形式かを確かめる(SVGや生のHTMLを含むページ用)
+/// 要素の入れ子・属性の引用符・文字参照・ルート要素が1つであることを見る
+/// 名前付きの文字参照はXMLで定義済みの5つだけ(&nbsp;などはDTDがないと使えない)
+/// * return - 整形式でなければその理由
+fn check_well_formed(xml: &str) -> Result<(), String> {
+    let mut stack: Vec<&str> = Vec::new();
+    let mut roots = 0;
+    let mut rest = xml.trim_start_matches('\u{feff}');
+    while !rest.is_empty() {
+        let lt = rest.find('<').unwrap_or(rest.len());
+        let text = &rest[..lt];
+        if stack.is_empty() && !text.trim().is_empty() {
+            return Err(String::from("text outside of the root element"));
+        }
+        for (i, _) in text.match_indices('&') {
+            if !is_entity(&text[i..]) {
+                return Err(String::from("unescaped &"));
+            }
+            let name = &text[i + 1..i + text[i..].find(';').unwrap()];
+            if !name.starts_with('#') && !["amp", "lt", "gt", "quot", "apos"].contains(&name) {
+                return Err(format!("undefined entity &{};", name));
+            }
+        }
+        rest = &rest[lt..];
+        if rest.is_empty() {
+            break;
+        }
+
+        // コメント・CDATA・処理命令・DOCTYPEは読み飛ばす
+        let skip = [("<!--", "-->"), ("<![CDATA[", "]]>"), ("<?", "?>"), ("<!DOCTYPE", ">")].iter()
+            .find(|(start, _)| rest.starts_with(start))
+            .map(|(start, end)| rest[start.len()..].find(end).map(|i| start.len() + i + end.len()));
+        match skip {
+            Some(Some(len)) => {
+                rest = &rest[len..];
+                continue;
+            }
+            Some(None) => return Err(String::from("unclosed comment or declaration")),
+            None => {}
+        }
+
+        // 属性値の中の>はタグの終わりではない
+        let mut quote = None;
+        let gt = rest.char_indices().find(|(_, c)| {
+            match (quote, *c) {
+                (None, '"') | (None, '\'') => quote = Some(*c),
+                (Some(q), c) if q == c => quote = None,
+                _ => {}
+            }
+            quote.is_none() && *c == '>'
+        });
+        let gt = match gt {
+            Some((gt, _)) => gt,
+            None => return Err(String::from("unclosed tag")),
+        };
+        let tag = &rest[1..gt];
+        rest = &rest[gt + 1..];
+
+        if let Some(name) = tag.strip_prefix('/') {
+            match stack.pop() {
+                Some(open) if open == name.trim() => {}
+                Some(open) => return Err(format!("</{}> does not close <{}>", name.trim(), open)),
+                None => return Err(format!("</{}> has no start tag", name.trim())),
+            }
+            continue;
+        }
+
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+        let name = &tag[..name_end];
+        if name.is_empty() {
+            return Err(String::from("tag without name"));
+        }
+        // 属性は name="value" または name='value'
+        let mut attributes = tag[name_end..].trim_start();
+        while !attributes.is_empty() {
+            let eq = match attributes.find('=') {
+                Some(eq) if !attributes[..eq].trim().is_empty() => eq,
+                _ => return Err(format!("malformed attribute in <{}>", name)),
+            };
+            let value = attributes[eq + 1..].trim_start();
+            let quote = match value.chars().next() {
+                Some(quote @ '"') | Some(quote @ '\'') => quote,
+                _ => return Err(format!("unquoted attribute in <{}>", name)),
+            };
+            let end = match value[1..].find(quote) {
+                Some(end) => end + 1,
+                None => return Err(format!("unclosed attribute in <{}>", name)),
+            };
+            if value[1..end].contains('<') {
+                return Err(format!("< in attribute of <{}>", name));
+            }
+            attributes = value[end + 1..].trim_start();
+        }
+
+        if stack.is_empty() {
+            roots += 1;
+            if roots > 1 {
+                return Err(String::from("more than one root element"));
+            }
+        }
+        if !self_closing {
+            stack.push(name);
+        }
+    }
+
+    match (stack.last(), roots) {
+        (Some(open), _) => Err(format!("<{}> is not closed", open)),
+        (None, 0) => Err(String::from("no root element")),
+        _ => Ok(()),
+    }
+}
+
+/// 本文から参照された画像
+struct Images<'a> {
+    /// コピー済みの画像のpathと、そのhref
+    hrefs: HashMap<PathBuf, String>,
+    /// 拡張子ごとのmedia-typeの上書き
+    media_types: &'a HashMap<String, String>,
+}
+
+/// htmlが参照しているローカルの画像をimagesフォルダにコピーし、参照先を書き換える
+fn embed_images(html: &str, source_path: &Path, oebps_path: &Path, staging: Staging, items: &mut Items, images: &mut Images) -> Result<String, failure::Error> {
+    let img_selector = match Selector::parse("img[src]") {
+        Ok(selector) => selector,
+        Err(_) => {
+            return Err(format_err!("[ERROR] selector parse error : {}:{}:{} ",file!(),line!(),column!()));
+        }
+    };
+    let srcs: Vec<String> = Html::parse_document(html)
+        .select(&img_selector)
+        .filter_map(|img| img.value().attr("src").map(|src| src.to_string()))
+        .collect();
+
+    let source_dir = source_path.parent().unwrap_or_else(|| Path::new("."));
+    let mut html = html.to_string();
+    for src in srcs {
+        // 外部の画像はそのまま
+        if src.starts_with("http://") || src.starts_with("https://") || src.starts_with("data:") {
+            continue;
+        }
+
+        let image_path = source_dir.join(percent_decode(&src));
+        if !image_path.is_file() {
+            warn!("{} が見つかりません", &src);
+            continue;
+        }
+        let image_path = image_path.canonicalize()?;
+
+        let href = match images.hrefs.get(&image_path) {
+            Some(href) => href.clone(),
+            None => {
+                let media_type = image_media_type(&image_path, images.media_types)?;
+
+                // SVGはXMLとして読めなければリーダーで表示できない
+                if media_type == "image/svg+xml" {
+                    if let Err(reason) = check_well_formed(&std::fs::read_to_string(&image_path)?) {
+                        return Err(format_err!("[ERROR] {:?} is not well-formed SVG : {}", &image_path, reason));
+                    }
+                }
+
+                // imagesフォルダ設置
+                let images_dir = oebps_path.join("images");
+                staging.create_dir(&images_dir)?;
+
+                // 名前が被ったら番号をつける
+                let stem = image_path.file_stem().map_or(String::from("image"), |stem| stem.to_string_lossy().replace(" ", "_"));
+                let ext = image_path.extension().map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()));
+                // 表紙の画像などmanifestにあるものとも重ねない
+                let mut name = format!("{}{}", stem, ext);
+                let mut count = 1;
+                let taken = |name: &str| {
+                    let href = format!("images/{}", name);
+                    images.hrefs.values().any(|image| *image == href) || items.items.iter().any(|item| item.href == href)
+                };
+                while taken(&name) {
+                    count += 1;
+                    name = format!("{}_{}{}", stem, count, ext);
+                }
 
-        self.tmp_files.meta_inf = Some(meta_inf);
+                staging.copy(&image_path, &images_dir.join(&name))?;
+                let href = format!("images/{}", name);
+                items.items.push(Item {
+                    id: Some(format!("image_{}", images.hrefs.len())),
+                    href: href.clone(),
+                    media_type,
+                    ..Item::default()
+                });
+                images.hrefs.insert(image_path, href.clone());
+                href
+            }
+        };
 
-        Ok(())
+        html = html.replace(&format!("src=\"{}\"", escape_xml(&src)), &format!("src=\"{}\"", href));
     }
 
-    /// OEBPSフォルダを設置する
-    /// * return - PathBuf of custom.css
-    fn add_oebps(&mut self, dir_path: &PathBuf) -> Result<PathBuf, failure::Error> {
-        // OEBPSフォルダ設置
-        let oebps_path = dir_path.join("OEBPS");
-        std::fs::create_dir_all(&oebps_path)?;
+    Ok(html)
+}
 
-        // スタイルフォルダ設置
-        let styles = oebps_path.join("styles");
-        std::fs::create_dir_all(&styles)?;
+/// 代替テキスト(alt)が空の画像を探す markdownの![代替テキスト](src)はそのままaltになる
+/// * return - altのない画像のsrc
+fn images_without_alt(html: &str) -> Result<Vec<String>, failure::Error> {
+    let img_selector = match Selector::parse("img") {
+        Ok(selector) => selector,
+        Err(_) => {
+            return Err(format_err!("[ERROR] selector parse error : {}:{}:{} ",file!(),line!(),column!()));
+        }
+    };
+    Ok(Html::parse_document(html)
+        .select(&img_selector)
+        .filter(|img| img.value().attr("alt").unwrap_or("").trim().is_empty())
+        .map(|img| img.value().attr("src").unwrap_or("").to_string())
+        .collect())
+}
 
-        // 縦書きスタイル
-        let vertical_css_path = styles.join("vertical.css");
-        let mut vertical_css = File::create(vertical_css_path)?;
-        vertical_css.write_all(include_str!("literals/vertical.css").as_bytes())?;
+/// comrakが出力した脚注にepub:typeを付け、idをファイルごとに区別する
+/// EPUB2ではepub:typeを付けず、脚注を囲む<section>を<div>にする
+fn mark_footnotes(html: &str, name: &str, epub_version: EpubVersion) -> Result<String, failure::Error> {
+    let note_selector = match Selector::parse("section.footnotes li[id]") {
+        Ok(selector) => selector,
+        Err(_) => {
+            return Err(format_err!("[ERROR] selector parse error : {}:{}:{} ",file!(),line!(),column!()));
+        }
+    };
+    let ids: Vec<String> = Html::parse_document(html)
+        .select(&note_selector)
+        .filter_map(|li| li.value().id().map(|id| id.to_string()))
+        .collect();
 
-        // custom style
-        let custom_css_path = styles.join("custom.css");
-        File::create(&custom_css_path)?;
+    if ids.is_empty() {
+        return Ok(html.to_string());
+    }
 
-        self.tmp_files.oebps = Some(oebps_path);
-        Ok(custom_css_path)
+    let (mut html, noteref, footnote) = match epub_version {
+        EpubVersion::V3 => (html.replace("<section class=\"footnotes\">", "<section class=\"footnotes\" epub:type=\"footnotes\">"),
+                            " epub:type=\"noteref\"", " epub:type=\"footnote\""),
+        EpubVersion::V2 => (footnotes_in_div(html), "", ""),
+    };
+    for id in ids {
+        // "fn1" -> "fnref1"
+        let ref_id = id.replacen("fn", "fnref", 1);
+        let new_id = format!("{}-{}", name, &id);
+        let new_ref_id = format!("{}-{}", name, &ref_id);
+
+        // 本文中の参照
+        html = html.replace(&format!("<a href=\"#{}\" id=\"{}\">", &id, &ref_id),
+                            &format!("<a href=\"#{}\" id=\"{}\"{}>", &new_id, &new_ref_id, noteref));
+        // 脚注本体
+        html = html.replace(&format!("<li id=\"{}\">", &id),
+                            &format!("<li id=\"{}\"{}>", &new_id, footnote));
+        // 戻りリンク
+        html = html.replace(&format!("<a href=\"#{}\" class=\"footnote-backref\">", &ref_id),
+                            &format!("<a href=\"#{}\" class=\"footnote-backref\">", &new_ref_id));
     }
 
-    /// .epubファイルを生成する
-    /// 生成に失敗したようなら、unzippedなゴミを片付ける
-    pub fn build(&mut self) -> Result<(), failure::Error> {
-        let res = match self.build_core() {
-            // failed
-            Err(e) => {
-                Err(e)
-            }
-            // succeeded
-            Ok(ok) => {
-                Ok(ok)
-            }
-        };
+    Ok(html)
+}
 
-        if !self.save_tmp_files {
-            // ファイル削除
-            self.remove_tmp_files();
+/// comrakが脚注を囲む<section class="footnotes">を<div>にする 脚注は本文の最後にある
+fn footnotes_in_div(html: &str) -> String {
+    let start = match html.find("<section class=\"footnotes\">") {
+        Some(start) => start,
+        None => return html.to_string(),
+    };
+    let mut html = html.to_string();
+    if let Some(end) = html[start..].find("</section>") {
+        html.replace_range(start + end..start + end + "</section>".len(), "</div>");
+    }
+    html.replace_range(start..start + "<section".len(), "<div");
+    html
+}
+
+/// 見出しのidにファイル名を付け、本全体で重ならないようにする
+/// 見出しのアンカーと、同じファイル内からのリンクも新しいidを指すようにする
+/// * return - 置き換えたxhtmlと(元のid, 新しいid)
+fn namespace_header_ids(html: &str, name: &str) -> Result<(String, Vec<(String, String)>), failure::Error> {
+    let header_selector = match Selector::parse("h1,h2,h3,h4,h5,h6") {
+        Ok(selector) => selector,
+        Err(_) => {
+            return Err(format_err!("[ERROR] selector parse error : {}:{}:{} ",file!(),line!(),column!()));
+        }
+    };
+    let anchor_selector = match Selector::parse("a[id]") {
+        Ok(selector) => selector,
+        Err(_) => {
+            return Err(format_err!("[ERROR] selector parse error : {}:{}:{} ",file!(),line!(),column!()));
         }
+    };
 
-        res
+    // (id, アンカーのhref)
+    let dom = Html::parse_document(html);
+    let mut ids = Vec::new();
+    for header in dom.select(&header_selector) {
+        let anchor = header.select(&anchor_selector).next();
+        let id = header.value().id().or_else(|| anchor.and_then(|a| a.value().id()));
+        if let Some(id) = id {
+            let href = anchor.and_then(|a| a.value().attr("href")).map(|href| href.to_string());
+            ids.push((id.to_string(), href));
+        }
     }
 
-    /// 一時ファイルを削除する
-    fn remove_tmp_files(&self) {
-        // pathを変数に代入
-        let TmpFiles {
-            mimetype, meta_inf, oebps
-        } = &self.tmp_files;
+    let mut html = html.to_string();
+    let mut renamed = Vec::with_capacity(ids.len());
+    for (id, href) in ids {
+        let new_id = format!("{}-{}", name, &id);
+        html = html.replace(&format!("id=\"{}\"", &id), &format!("id=\"{}\"", &new_id));
+        html = html.replace(&format!("href=\"#{}\"", &id), &format!("href=\"#{}\"", &new_id));
+        // comrakのアンカーはidと違うslugを指しているので、見出し自身を指すようにする
+        if let Some(href) = href {
+            html = html.replace(&format!("<a href=\"{}\" aria-hidden=\"true\" class=\"anchor\" id=\"{}\">", &href, &new_id),
+                                &format!("<a href=\"#{}\" aria-hidden=\"true\" class=\"anchor\" id=\"{}\">", &new_id, &new_id));
+        }
+        renamed.push((id, new_id));
+    }
+
+    Ok((html, renamed))
+}
+
+/// mdファイルをlevelの見出し(#の数がlevelのATX見出し)の前で分ける
+/// front matterは最初の部分に残す コードブロックの中の#は見出しとしない
+fn split_chapters(md: &str, source_path: &Path, level: u8) -> Result<Vec<String>, failure::Error> {
+    let (_, body) = FrontMatter::split(md, source_path)?;
+    let head = &md[..md.len() - body.len()];
+    let marker = "#".repeat(level as usize);
 
-        // 存在すれば削除
-        // エラーを拾ったときにもゴミ掃除をしたいので、エラー次第ではどれかが存在しないこともありうる
-        mimetype.clone().map(|path| std::fs::remove_file(path));
-        meta_inf.clone().map(|path| std::fs::remove_dir_all(path));
-        oebps.clone().map(|path| std::fs::remove_dir_all(path));
+    let mut starts = vec![0];
+    let mut fence: Option<&str> = None;
+    let mut offset = 0;
+    for line in body.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
+            }
+        } else if let Some(marker) = ["```", "~~~"].iter().find(|marker| trimmed.starts_with(**marker)) {
+            fence = Some(marker);
+        } else if line.len() - trimmed.len() <= 3
+            && trimmed.starts_with(&marker)
+            && trimmed[marker.len()..].starts_with(&[' ', '\t', '\n', '\r'][..])
+            // 前の部分が空なら分けない
+            && !body[starts[starts.len() - 1]..offset].trim().is_empty() {
+            starts.push(offset);
+        }
+        offset += line.len();
     }
 
-    /// .epubファイルを生成する
-    fn build_core(&mut self) -> Result<(), failure::Error> {
-        let souce_file_path = self.source_file.clone();
-        let dir_path = PathBuf::from(".");
+    let mut chapters: Vec<String> = starts.iter().enumerate()
+        .map(|(i, start)| {
+            let end = starts.get(i + 1).cloned().unwrap_or(body.len());
+            body[*start..end].to_string()
+        })
+        .collect();
+    chapters[0].insert_str(0, head);
+    Ok(chapters)
+}
 
-        // mimetypeファイル設置
-        self.add_mimetype(&dir_path)?;
+/// 書き出したxhtml (mdファイル, xhtmlファイル, 見出しの(元のid, 新しいid))
+type Chapter<'a> = (&'a PathBuf, String, Vec<(String, String)>);
 
-        // META-INFフォルダ, container.xmlを設置
-        self.add_meta_inf(&dir_path)?;
+/// 一つのmdファイルから分けたxhtmlの間で、ほかのファイルの見出しへのリンクを直す
+fn link_split_chapters(oebps_path: &Path, chapters: &[Chapter]) -> Result<(), failure::Error> {
+    for (path, href, _) in chapters {
+        let targets: Vec<(&String, String)> = chapters.iter()
+            .filter(|(other_path, other_href, _)| other_path == path && other_href != href)
+            .flat_map(|(_, other_href, ids)| ids.iter().map(move |(id, new_id)| (id, format!("{}#{}", other_href, new_id))))
+            .collect();
+        if targets.is_empty() {
+            continue;
+        }
 
-        // OEBPSフォルダ, styleフォルダ, vertical.css設置
-        let custom_css_path = self.add_oebps(&dir_path)?;
+        let xhtml_path = oebps_path.join(href);
+        let mut html = std::fs::read_to_string(&xhtml_path)?;
+        for (id, target) in targets {
+            html = html.replace(&format!("href=\"#{}\"", id), &format!("href=\"{}\"", target));
+        }
+        File::create(&xhtml_path)?.write_all(html.as_bytes())?;
+    }
+    Ok(())
+}
 
-        let (mimetype, meta_inf, oebps_path) = match &self.tmp_files {
-            TmpFiles {
-                mimetype: Some(mimetype),
-                meta_inf: Some(meta_inf),
-                oebps: Some(oebps_path),
-            } => {
-                (mimetype, meta_inf, oebps_path)
-            }
-            _ => {
-                return Err(format_err!("[ERROR] file error : {}:{}:{} ",file!(),line!(),column!()));
+/// 章のxhtmlを一つのファイルにまとめ、manifest・目次・リンクをそちらに向ける
+/// 各章のbodyの中身はid="元のファイル名"のdivで囲み、間に<hr />を入れる
+/// 見出しと脚注のidは章ごとに名前空間が付いているので重ならない
+fn merge_chapters(oebps_path: &Path, chapters: &[Chapter], staging: Staging, items: &mut Items, toc_items: &mut [ToCItem], title: &str) -> Result<(), failure::Error> {
+    let hrefs: Vec<&String> = chapters.iter().map(|(_, href, _)| href).collect();
+    let first = match items.items.iter().position(|item| hrefs.contains(&&item.href)) {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    let mut merged: Vec<Item> = Vec::new();
+    items.items.retain(|item| {
+        if hrefs.contains(&&item.href) {
+            merged.push(Item { href: item.href.clone(), properties: item.properties.clone(), ..Item::default() });
+            return false;
+        }
+        true
+    });
+    let name = xhtml_name("content", items);
+    let href = format!("{}.xhtml", name);
+
+    // 目次は元のファイル名をidとして新しいファイルを指す
+    for toc_item in toc_items.iter_mut() {
+        if merged.iter().any(|item| item.href == format!("{}.xhtml", toc_item.filename)) {
+            if toc_item.id.is_none() {
+                toc_item.id = Some(toc_item.filename.clone());
             }
-        };
+            toc_item.filename = name.clone();
+        }
+    }
+
+    // MathMLやSVGはどれかの章にあれば示す
+    let mut properties: Vec<&str> = merged.iter()
+        .filter_map(|item| item.properties.as_deref())
+        .flat_map(|properties| properties.split(' '))
+        .collect();
+    properties.sort();
+    properties.dedup();
+    items.items.insert(first, Item {
+        href: href.clone(),
+        properties: if properties.is_empty() { None } else { Some(properties.join(" ")) },
+        epub_type: Some(String::from("bodymatter")),
+        ..Item::default()
+    });
 
-        // custom.cssに書き込み
-        if let Some(path) = &self.style {
-            // オリジナルのcssを読み取る
-            let mut css = String::new();
-            let mut original_css = File::open(path)?;
-            original_css.read_to_string(&mut css)?;
-            // custom.cssに書き込み
-            let mut custom_css = File::create(custom_css_path)?;
-            custom_css.write_all(css.as_bytes())?;
+    if staging.dry_run {
+        return Ok(());
+    }
+    let mut head = String::new();
+    let mut bodies = Vec::with_capacity(merged.len());
+    for item in &merged {
+        let xhtml_path = oebps_path.join(&item.href);
+        let html = std::fs::read_to_string(&xhtml_path)?;
+        let body_start = html.find("<body")
+            .and_then(|start| html[start..].find('>').map(|end| start + end + 1))
+            .ok_or_else(|| format_err!("[ERROR] {:?} has no body.", xhtml_path))?;
+        let body_end = html.rfind("</body>").unwrap_or(html.len());
+        if head.is_empty() {
+            head = html[..body_start].to_string();
         }
+        let id = item.href.trim_end_matches(".xhtml");
+        bodies.push(format!("<div class=\"chapter\" id=\"{}\">\n{}\n</div>\n", escape_xml(id), html[body_start..body_end].trim()));
+        staging.remove_file(&xhtml_path)?;
+    }
+    // タイトルは本のタイトルにする
+    if let (Some(start), Some(end)) = (head.find("<title>"), head.find("</title>")) {
+        head.replace_range(start + "<title>".len()..end, &escape_xml(title));
+    }
+    let mut html = format!("{}\n{}</body>\n</html>\n", head, bodies.join("<hr class=\"chapter-break\" />\n"));
 
+    // 章の間のリンクも新しいファイルの中へ
+    for item in &merged {
+        let id = item.href.trim_end_matches(".xhtml");
+        html = html.replace(&format!("href=\"{}#", item.href), &format!("href=\"{}#", href))
+            .replace(&format!("href=\"{}\"", item.href), &format!("href=\"{}#{}\"", href, id));
+    }
+    staging.write(&oebps_path.join(&href), html.as_bytes())
+        .map_err(failure::Error::from)
+}
 
-        // ファイル読み込み&変換
-        let mut items = Items::default();
-        let vertical = &self.vertical;
-        let mut toc_items = Vec::new();
-        if souce_file_path.is_file() {
-            convert(&souce_file_path, &oebps_path, &mut items, &mut toc_items, vertical.clone())?;
-        } else {
-            // ディレクトリから中身一覧を取得
-            let mut entries: Vec<_> = std::fs::read_dir(&souce_file_path)?
-                .map(|r| r.unwrap())
-                .collect();
-            // 並べ替え
-            entries.sort_by_key(|e| e.path());
-            // convert
-            for entry in entries {
-                let path = entry.path();
-                if let Some(ext_os) = path.extension() {
-                    if let Some(ext) = ext_os.to_str() {
-                        if ext == "md" {
-                            convert(&path, &oebps_path, &mut items, &mut toc_items, vertical.clone())?;
-                        }
-                    }
-                }
+/// 章を変換できなかったとき、どのファイルかわかるエラーにする
+/// continue_on_errorなら警告を出してfailuresに記録し、残りの章を続ける
+fn chapter_failed(path: &Path, e: failure::Error, continue_on_error: bool, failures: &mut Vec<(PathBuf, String)>) -> Result<(), failure::Error> {
+    let message = e.to_string();
+    let message = message.trim_start_matches("[ERROR] ");
+    if !continue_on_error {
+        return Err(format_err!("[ERROR] failed to convert {:?} : {}", path, message));
+    }
+    warn!("{} を変換できないため飛ばします: {}", path.display(), message);
+    failures.push((path.to_path_buf(), message.to_string()));
+    Ok(())
+}
+
+/// 変換途中のmdファイル
+struct Rendered {
+    front_matter: FrontMatter,
+    /// 画像と脚注を処理する前のxhtml
+    html: String,
+    /// xhtmlのファイル名の元 なければmdファイル名
+    stem: Option<String>,
+    /// EPUB2ではepub:typeや<section>を書かない
+    epub_version: EpubVersion,
+}
+
+/// 雛形に必ずなければならない置き換え場所
+const TEMPLATE_PLACEHOLDERS: [&str; 3] = ["{title}", "{style_links}", "{body}"];
+
+/// 雛形の{name}を置き換える 置き換えた値の中はもう一度置き換えない
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = values.iter()
+            .find(|(name, _)| rest[1..].starts_with(name) && rest[1 + name.len()..].starts_with('}'));
+        match value {
+            Some((name, value)) => {
+                filled.push_str(value);
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
             }
         }
+    }
+    filled.push_str(rest);
+    filled
+}
 
-        // package.opf設置
-        let mut package_opf = File::create(
-            &oebps_path.join("package.opf"))?;
+/// 全ページで共通の変換の設定
+struct PageOptions<'a> {
+    comrak_options: ComrakOptions,
+    highlighter: &'a Highlighter,
+    /// ページの雛形
+    template: &'a str,
+    /// 追加で読み込むcssのlink要素
+    stylesheets: &'a str,
+    /// 本の言語 front matterで上書きできる
+    language: &'a str,
+    /// viewportのmeta要素 指定がなければ空
+    viewport: &'a str,
+    math: bool,
+    epub_version: EpubVersion,
+}
 
-        // package.opf書き込み準備
-        let metadata = MetaData {
-            title: &self.title,
-            creator: &self.creator,
-            language: &self.language,
-            id: &self.id,
-        };
+/// mdファイルの中身をxhtmlに変換してコードブロックを色付けする
+/// ほかのファイルに依存しないので並列に呼べる
+fn render(source_path: &Path, md: &str, options: &PageOptions) -> Result<Rendered, failure::Error> {
+    use comrak::markdown_to_html;
 
-        // package.opf書き込み
-        let package = Package { metadata, items };
-        package_opf.write_all(&package.to_opf(self.vertical.clone()).as_bytes())?;
+    // front matterは取り除いてから変換する
+    let (front_matter, md) = FrontMatter::split(md, source_path)?;
+    // 数式はmarkdownとして解釈されないよう目印に置き換えておく
+    let (md, formulas) = if options.math {
+        extract_math(md)
+    } else {
+        (md.to_string(), Vec::new())
+    };
+    let md = md.as_str();
+    let page_title = match &front_matter.title {
+        Some(title) => escape_xml(title),
+        None => file_name_of(source_path)?.to_string(),
+    };
+    // convert
+    let language = escape_xml(front_matter.lang.as_deref().unwrap_or(options.language));
+    let body = mark_highlights(&escape_url_ampersands(&markdown_to_html(md, &options.comrak_options)));
+    let epub_type = epub_type_of(&front_matter, source_path)?;
+    let division = division_of(&epub_type);
+    let epub_type = escape_xml(&epub_type);
+    let html = fill_template(options.template, &[
+        ("lang", &language),
+        ("epub_type", &epub_type),
+        ("division", division),
+        ("viewport", options.viewport),
+        ("style_links", options.stylesheets),
+        ("title", &page_title),
+        ("body", &body),
+    ]);
 
-        // navigation.opf作成
-        let mut navigation_opf = File::create(
-            &oebps_path.join("navigation.xhtml"))?;
-        let toc = ToC::new(toc_items);
+    // コードブロック
+    let html = options.highlighter.highlight(&html);
 
-        navigation_opf.write_all(&toc.to_nav(self.toc_level, self.vertical, Some(String::from("目次"))).as_bytes())?;
+    // タスクリスト
+    let html = mark_task_items(&html);
 
+    // 数式
+    let html = insert_math(&html, &formulas, source_path);
 
-        // zip圧縮
-        self.make(&mimetype, &meta_inf, &oebps_path)?;
-//        self.make_with_command(mimetype, meta_inf, oebps_path)?;
+    Ok(Rendered { front_matter, html, stem: None, epub_version: options.epub_version })
+}
 
-        Ok(())
-    }
+/// 数式を置き換えた目印 markdownとして解釈されない文字だけを使う
+fn math_placeholder(index: usize) -> String {
+    format!("REPUBMATH{}X", index)
+}
 
-    /// zip前のフォルダのpathから.epubを生成する
-    fn make(&self, mimetype: &PathBuf, meta_inf: &PathBuf, oebps: &PathBuf) -> ZipResult<()> {
-        //        use zip::result::ZipResult;
-        use zip::write::{FileOptions, ZipWriter};
+/// markdownから$...$と$$...$$を取り出し、目印に置き換える
+/// コードブロックとコードスパンの中、\$は数式として扱わない
+/// * return - 置き換えたmarkdownと(LaTeX, ブロックか)の一覧
+fn extract_math(md: &str) -> (String, Vec<(String, bool)>) {
+    let mut out = String::with_capacity(md.len());
+    let mut formulas = Vec::new();
+    let mut fence: Option<&str> = None;
+    // 複数行にまたがる$$...$$
+    let mut block: Option<String> = None;
+
+    for line in md.split_inclusive('\n') {
+        let trimmed = line.trim_start();
 
-        let epub_path = PathBuf::from(&format!("{}.epub", &self.title));
-        let epub = match File::create(&epub_path) {
-            Ok(file) => {
-                file
+        // コードブロックの中はそのまま
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
             }
-            Err(_) => {
-                std::fs::remove_file(&epub_path)?;
-                File::create(&epub_path)?
+            out.push_str(line);
+            continue;
+        }
+        if block.is_none() {
+            if let Some(marker) = ["```", "~~~"].iter().find(|marker| trimmed.starts_with(**marker)) {
+                fence = Some(marker);
+                out.push_str(line);
+                continue;
+            }
+        }
+
+        let mut rest = line;
+        while !rest.is_empty() {
+            if let Some(latex) = &mut block {
+                match rest.find("$$") {
+                    Some(end) => {
+                        latex.push_str(&rest[..end]);
+                        out.push_str(&math_placeholder(formulas.len()));
+                        formulas.push((latex.trim().to_string(), true));
+                        block = None;
+                        rest = &rest[end + 2..];
+                    }
+                    None => {
+                        latex.push_str(rest);
+                        rest = "";
+                    }
+                }
+                continue;
             }
-        };
 
-        let mut writer = ZipWriter::new(epub);
-        let method = CompressionMethod::Deflated;
+            let next = match rest.find(&['`', '$', '\\'][..]) {
+                Some(next) => next,
+                None => {
+                    out.push_str(rest);
+                    break;
+                }
+            };
+            out.push_str(&rest[..next]);
+            rest = &rest[next..];
 
-        // mimetype
-        {
-            writer.start_file(mimetype.to_str().unwrap(),
-                              FileOptions::default().compression_method(CompressionMethod::Stored))?;
-            writer.write(std::fs::read_to_string(mimetype)?.as_bytes())?;
+            if rest.starts_with('\\') {
+                // エスケープされた文字はそのまま
+                let len = rest.chars().nth(1).map_or(1, |c| 1 + c.len_utf8());
+                out.push_str(&rest[..len]);
+                rest = &rest[len..];
+            } else if rest.starts_with('`') {
+                // コードスパン 同じ数のバッククォートまで
+                let ticks = rest.len() - rest.trim_start_matches('`').len();
+                let marker = &rest[..ticks];
+                let len = match rest[ticks..].find(marker) {
+                    Some(end) => ticks + end + ticks,
+                    None => ticks,
+                };
+                out.push_str(&rest[..len]);
+                rest = &rest[len..];
+            } else if rest.starts_with("$$") {
+                block = Some(String::new());
+                rest = &rest[2..];
+            } else {
+                // $の直後と閉じる$の直前は空白でなく、閉じる$の後は数字でない("$5と$10"は数式にしない)
+                let body = &rest[1..];
+                let end = if body.starts_with(char::is_whitespace) {
+                    None
+                } else {
+                    body.char_indices()
+                        .filter(|(_, c)| *c == '$')
+                        .map(|(i, _)| i)
+                        .find(|i| *i > 0
+                            && !body[..*i].ends_with(char::is_whitespace)
+                            && !body[..*i].ends_with('\\')
+                            && !body[*i + 1..].starts_with(|c: char| c.is_ascii_digit()))
+                };
+                match end {
+                    Some(end) => {
+                        out.push_str(&math_placeholder(formulas.len()));
+                        formulas.push((body[..end].to_string(), false));
+                        rest = &body[end + 1..];
+                    }
+                    None => {
+                        out.push('$');
+                        rest = body;
+                    }
+                }
+            }
         }
+    }
+
+    // 閉じていない$$は元に戻す
+    if let Some(latex) = block {
+        out.push_str("$$");
+        out.push_str(&latex);
+    }
+
+    (out, formulas)
+}
 
-        // META-INF
-        writer.add_directory_from_path(meta_inf,
-                                       FileOptions::default().compression_method(method))?;
+/// 目印をMathMLに置き換える
+/// 変換できない数式は警告を出し、元のLaTeXを残す
+fn insert_math(html: &str, formulas: &[(String, bool)], source_path: &Path) -> String {
+    use latex2mathml::{latex_to_mathml, DisplayStyle};
 
-        // inner of META-INF
-        for entry in std::fs::read_dir(&meta_inf)? {
-            let path = entry?.path();
-            if path.is_file() {
-                writer.start_file_from_path(path.as_path(),
-                                            FileOptions::default().compression_method(method))?;
-                writer.write(std::fs::read_to_string(path)?.as_bytes())?;
+    let mut html = html.to_string();
+    // 目印の末尾のXで"REPUBMATH1X"と"REPUBMATH10X"を区別する
+    for (i, (latex, block)) in formulas.iter().enumerate() {
+        let placeholder = math_placeholder(i);
+        let display = if *block { DisplayStyle::Block } else { DisplayStyle::Inline };
+        let mathml = match latex_to_mathml(latex, display) {
+            Ok(mathml) => mathml,
+            Err(e) => {
+                warn!("{:?} の数式 {} を変換できません: {}", source_path, latex, e);
+                let delimiter = if *block { "$$" } else { "$" };
+                escape_xml(&format!("{}{}{}", delimiter, latex, delimiter))
             }
+        };
+        // 段落だけの数式は段落ごと置き換える
+        let paragraph = format!("<p>{}</p>", placeholder);
+        if *block && html.contains(&paragraph) {
+            html = html.replacen(&paragraph, &mathml, 1);
+        } else {
+            html = html.replacen(&placeholder, &mathml, 1);
         }
+    }
+    html
+}
 
-        // OEBPS
-        writer.add_directory_from_path(oebps, FileOptions::default().compression_method(method))?;
+/// チェックボックスで始まるliにclassを付け、cssで行頭の記号を消せるようにする
+/// comrakはチェックボックスを<input ... />と閉じて書き出す
+fn mark_task_items(html: &str) -> String {
+    let checkbox = "<input type=\"checkbox\" disabled=\"\"";
+    html
+        // 項目の間に空行のないリスト
+        .replace(&format!("<li>{}", checkbox),
+                 &format!("<li class=\"task-list-item\">{}", checkbox))
+        // 項目が段落になるリスト
+        .replace(&format!("<li>\n<p>{}", checkbox),
+                 &format!("<li class=\"task-list-item\">\n<p>{}", checkbox))
+}
 
-        // inner of OEBPS
-        for entry in std::fs::read_dir(&oebps)? {
-            let path = entry?.path();
-            if path.is_file() {
-                writer.start_file_from_path(path.as_path(), FileOptions::default())?;
-                writer.write(std::fs::read_to_string(path)?.as_bytes())?;
+/// 本文の==text==を<mark>text</mark>にする comrakには蛍光ペンの拡張がない
+/// タグの中と<code>・<pre>の中はそのまま
+fn mark_highlights(html: &str) -> String {
+    let mut marked = String::with_capacity(html.len());
+    let mut code_depth = 0usize;
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            let tag = &rest[..end];
+            if tag.starts_with("<code") || tag.starts_with("<pre") {
+                code_depth += 1;
+            } else if tag.starts_with("</code") || tag.starts_with("</pre") {
+                code_depth = code_depth.saturating_sub(1);
+            }
+            marked.push_str(tag);
+            rest = &rest[end..];
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            if code_depth == 0 {
+                marked.push_str(&mark_text(&rest[..end]));
+            } else {
+                marked.push_str(&rest[..end]);
             }
+            rest = &rest[end..];
         }
+    }
+    marked
+}
 
-        // styles
-        let styles = oebps.join("styles");
-        writer.add_directory_from_path(&styles, FileOptions::default().compression_method(method))?;
-        for entry in std::fs::read_dir(&styles)? {
-            let path = entry?.path();
-            if path.is_file() {
-                writer.start_file_from_path(path.as_path(), FileOptions::default())?;
-                writer.write(std::fs::read_to_string(path)?.as_bytes())?;
+/// テキストの==text==を<mark>にする "a == b"のように==の内側が空白のものは置き換えない
+fn mark_text(text: &str) -> String {
+    let mut marked = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("==") {
+        let inner = &rest[start + 2..];
+        let close = inner.find("==").filter(|end| {
+            *end > 0
+                && !inner.starts_with(|c: char| c.is_whitespace() || c == '=')
+                && !inner[..*end].ends_with(char::is_whitespace)
+        });
+        match close {
+            Some(end) => {
+                marked.push_str(&rest[..start]);
+                marked.push_str(&format!("<mark>{}</mark>", &inner[..end]));
+                rest = &inner[end + 2..];
+            }
+            None => {
+                marked.push_str(&rest[..start + 2]);
+                rest = inner;
             }
         }
-
-        writer.finish()?;
-
-        Ok(())
     }
+    marked.push_str(rest);
+    marked
+}
 
-    /// zip前のフォルダのpathからコマンドを用いて.epubを生成する
-    #[allow(dead_code)]
-    fn make_with_command(&self, mimetype: &PathBuf, meta_inf: &PathBuf, oebps: &PathBuf) -> Result<(), failure::Error> {
-        use std::process::Command;
-
-        if cfg!(target_os = "macos") {
-            let epubname = &format!("{}.epub", &self.title);
-            Command::new("zip")
-                .arg("-x0q")
-                .arg(epubname)
-                .arg(mimetype.to_str().unwrap())
-                .output().expect("Missed zip mimetype");
-            Command::new("zip")
-                .arg("-Xr9Dq")
-                .arg(epubname)
-                .arg(meta_inf.to_str().unwrap())
-                .output().expect("Missed zip META-INF");
-            Command::new("zip")
-                .arg("-Xr9Dq")
-                .arg(epubname)
-                .arg(oebps.to_str().unwrap())
-                .output().expect("Missed zip OEBPS");
+/// href・src属性のURLの中の&を&amp;にする
+/// comrakはURLの&をそのまま書き出すため、XHTMLとして読めなくなる
+fn escape_url_ampersands(html: &str) -> String {
+    let mut escaped = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = [" href=\"", " src=\""].iter().filter_map(|attr| rest.find(attr).map(|i| i + attr.len())).min() {
+        escaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find('"').unwrap_or(rest.len());
+        let url = &rest[..end];
+        for (i, c) in url.char_indices() {
+            if c == '&' && !is_entity(&url[i..]) {
+                escaped.push_str("&amp;");
+            } else {
+                escaped.push(c);
+            }
         }
+        rest = &rest[end..];
+    }
+    escaped.push_str(rest);
+    escaped
+}
 
-        Ok(())
+/// &amp;や&#38;のような文字参照で始まるか
+fn is_entity(text: &str) -> bool {
+    let end = match text.find(';') {
+        Some(end) => end,
+        None => return false,
+    };
+    let name = &text[1..end];
+    match name.strip_prefix('#') {
+        Some(code) => match code.strip_prefix('x').or_else(|| code.strip_prefix('X')) {
+            Some(hex) => !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()),
+            None => !code.is_empty() && code.chars().all(|c| c.is_ascii_digit()),
+        },
+        None => !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric()),
     }
 }
 
-use scraper::{Html, Selector};
-use zip::CompressionMethod;
-use zip::result::ZipResult;
-use core::borrow::BorrowMut;
+/// MathMLやSVGを含むページはmanifestで示す
+fn page_properties(html: &str) -> Option<String> {
+    let mut properties = Vec::new();
+    if html.contains("<math ") {
+        properties.push("mathml");
+    }
+    if html.contains("<svg ") || html.contains("<svg>") {
+        properties.push("svg");
+    }
+    if properties.is_empty() { None } else { Some(properties.join(" ")) }
+}
 
-/// domからheaderを読み取り、li要素のVecを返す
-fn toc_from_dom(dom: Html, filename: &str) -> Result<Vec<ToCItem>, failure::Error> {
-    let header_selector = match Selector::parse("h1,h2,h3,h4,h5") {
-        Ok(selector) => selector,
-        Err(_) => {
-            return Err(format_err!("[ERROR] selector parse error : {}:{}:{} ",file!(),line!(),column!()));
-        }
-    };
-    let headers = dom.select(&header_selector);
+/// xhtmlを変換せずにそのまま書き出す 目次は見出しから作る
+fn append_raw_chapter(source_path: &Path, spine_position: Option<usize>, oebps_path: &Path, staging: Staging, items: &mut Items, toc_items: &mut Vec<ToCItem>) -> Result<(), failure::Error> {
+    let xhtml = std::fs::read_to_string(source_path)?;
+    check_well_formed(&xhtml)
+        .map_err(|e| format_err!("[ERROR] {:?} is not well-formed XHTML : {}", source_path, e))?;
 
-    let toc_items: Vec<ToCItem> = headers.map(|header| {
-        // header text
-        let title = header.text().next().map_or(String::from("UNWRAP ERROR: HEADER TEXT"), |text| text.to_string());
-        let level = match header.value().name()[1..].parse::<u8>() {
-            Ok(level) => level,
-            Err(_) => 6,
-        };
-
-        let element_ref = header.select(&Selector::parse("a[id]")
-            .expect(&format!("[ERROR] selector parse error : {}:{}:{} ", file!(), line!(), column!())))
-            .next();
-
-        match element_ref {
-            // idあり -> a要素
-            Some(id) => {
-                let id = id.value().id().map(|id| id.to_string());
-                ToCItem {
-                    is_dummy: false,
-                    filename: filename.to_string(),
-                    id,
-                    title,
-                    level,
-                    ..ToCItem::default()
-                }
-            }
-            // idなし -> span要素
-            None => {
-                ToCItem {
-                    is_dummy: false,
-                    filename: filename.to_string(),
-                    title,
-                    level,
-                    ..ToCItem::default()
-                }
-            }
-        }
-    }).collect();
+    let name = xhtml_name(file_stem_of(source_path)?, items);
 
-    Ok(toc_items)
+    // toc
+    let mut file_toc_items = toc_from_dom(Html::parse_document(&xhtml), &name)?;
+    if file_toc_items.is_empty() {
+        file_toc_items.push(ToCItem {
+            is_dummy: false,
+            filename: name.clone(),
+            title: humanize_file_stem(file_stem_of(source_path)?),
+            ..ToCItem::default()
+        });
+    }
+    toc_items.append(&mut file_toc_items);
+
+    let xhtml_name = format!("{}.xhtml", name);
+    staging.write(&oebps_path.join(&xhtml_name), xhtml.as_bytes())?;
+
+    items.items.push(
+        Item {
+            href: xhtml_name,
+            properties: page_properties(&xhtml),
+            spine_position,
+            ..Item::default()
+        }
+    );
+    Ok(())
 }
 
-fn convert(source_path: &PathBuf, oebps_path: &PathBuf, items: &mut Items, toc_items: &mut Vec<ToCItem>, vertical: bool) -> Result<(), failure::Error> {
-    use comrak::{markdown_to_html, ComrakOptions};
+/// 画像・脚注・目次を処理してxhtmlを書き出す
+/// 画像やファイル名の重複を避けるため、ファイルの順に呼ぶ
+/// * return - 見出しの(元のid, 新しいid)
+fn convert(source_path: &Path, rendered: Rendered, oebps_path: &Path, staging: Staging, items: &mut Items, toc_items: &mut Vec<ToCItem>, images: &mut Images) -> Result<Vec<(String, String)>, failure::Error> {
+    let Rendered { front_matter, html, stem, epub_version } = rendered;
 
-    // source file
-    let mut md_file = File::open(&source_path)?;
-    // content
-    let mut md = String::new();
-    md_file.read_to_string(&mut md)?;
-    // convert
-    let comrak_options = ComrakOptions {
-        ext_header_ids: Some("header-".to_string()),
-        hardbreaks: true,
-        ..ComrakOptions::default()
-    };
-    let html = format!(include_str!("literals/template.xhtml"),
-                       if vertical { "<link type=\"text/css\" rel=\"stylesheet\" href=\"styles/vertical.css\" />" } else { "" }
-                       , source_path.file_name().unwrap().to_str().unwrap(), markdown_to_html(&md, &comrak_options));
+    // 画像
+    let html = embed_images(&html, source_path, oebps_path, staging, items, images)?;
 
     // source file name
-    let name = source_path.file_stem().unwrap().to_str().unwrap().replace(" ", "_");
+    let name = match &stem {
+        Some(stem) => xhtml_name(stem, items),
+        None => xhtml_name(file_stem_of(source_path)?, items),
+    };
+
+    // 脚注
+    let html = mark_footnotes(&html, &name, epub_version)?;
+
+    // 見出しのid
+    let (html, ids) = namespace_header_ids(&html, &name)?;
+
+    // XHTML 1.1にはaria属性がなく、disabledは"disabled"しか書けない
+    let html = match epub_version {
+        EpubVersion::V2 => html.replace(" aria-hidden=\"true\"", "").replace(" disabled=\"\"", " disabled=\"disabled\""),
+        EpubVersion::V3 => html,
+    };
 
     // toc
     let dom = Html::parse_document(&html);
-    toc_items.append(&mut toc_from_dom(dom, &name)?);
+    let mut file_toc_items = toc_from_dom(dom, &name)?;
+    // 見出しのないページも目次から開けるよう、ファイル名で載せる
+    if file_toc_items.is_empty() {
+        file_toc_items.push(ToCItem {
+            is_dummy: false,
+            filename: name.clone(),
+            title: humanize_file_stem(file_stem_of(source_path)?),
+            ..ToCItem::default()
+        });
+    }
+    // front matterのタイトルで章の見出しを置き換える
+    if let (Some(title), Some(first)) = (&front_matter.title, file_toc_items.first_mut()) {
+        first.title = title.clone();
+    }
+    toc_items.append(&mut file_toc_items);
 
-    // xml path
-    let mut xhtml_path = PathBuf::from(name);
-    xhtml_path.set_extension("xhtml");
-    let xhtml_file_path = &oebps_path.join(&xhtml_path);
     // xml file
-    File::create(xhtml_file_path)?.write_all(&html.as_bytes())?;
+    let xhtml_name = format!("{}.xhtml", name);
+    staging.write(&oebps_path.join(&xhtml_name), html.as_bytes())?;
 
     items.items.push(
         Item {
-            href: xhtml_path.file_name().unwrap().to_str().unwrap().to_string(),
+            href: xhtml_name,
+            properties: page_properties(&html),
+            spine_position: front_matter.spine_position,
+            linear: front_matter.linear,
+            epub_type: Some(epub_type_of(&front_matter, source_path)?),
             ..Item::default()
         }
     );
 
-    Ok(())
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_manifest_rejects_unlisted_file() {
+        let oebps = std::env::temp_dir().join(format!("repub-unit-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(oebps.join("images")).unwrap();
+        std::fs::write(oebps.join("book.xhtml"), "").unwrap();
+        std::fs::write(oebps.join("images").join("stray.png"), "").unwrap();
+
+        let manifest = "<item id=\"book\" href=\"book.xhtml\" media-type=\"application/xhtml+xml\" />";
+        let result = check_manifest(&oebps, &oebps, manifest);
+        let listed = format!("{}\n<item id=\"stray\" href=\"images/stray.png\" media-type=\"image/png\" />", manifest);
+        let listed_result = check_manifest(&oebps, &oebps, &listed);
+        std::fs::remove_dir_all(&oebps).unwrap();
+
+        let err = result.err().unwrap();
+        assert_eq!(err.to_string(), "[ERROR] images/stray.png is not listed in the manifest.");
+        assert!(listed_result.is_ok());
+    }
 }
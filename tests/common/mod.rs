@@ -0,0 +1,100 @@
+//! 結合テストの共通部分
+//! fixtureはtests/fixtures以下に置き、ケースごとにディレクトリを分ける
+
+#![allow(dead_code)]
+
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use repub::RepubBuilder;
+use zip::ZipArchive;
+
+/// 出力先が重ならないよう、ビルドごとに番号を振る
+static BUILD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub type Epub = ZipArchive<Cursor<Vec<u8>>>;
+
+/// tests/fixtures以下のpath
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name)
+}
+
+/// fixtureを変換し、できた.epubを読み込む
+pub fn build(name: &str) -> Epub {
+    build_with(name, |_| {})
+}
+
+/// タイトル・作者・言語のほかに設定を加えて変換する
+pub fn build_with<F: FnOnce(&mut RepubBuilder)>(name: &str, configure: F) -> Epub {
+    try_build_with(name, configure).unwrap()
+}
+
+/// 失敗するケース用
+pub fn try_build_with<F: FnOnce(&mut RepubBuilder)>(name: &str, configure: F) -> Result<Epub, failure::Error> {
+    Ok(ZipArchive::new(Cursor::new(build_bytes_with(name, configure)?))?)
+}
+
+/// 変換した.epubをバイト列のまま返す zipのヘッダーを直接確かめる用
+pub fn build_bytes_with<F: FnOnce(&mut RepubBuilder)>(name: &str, configure: F) -> Result<Vec<u8>, failure::Error> {
+    let output = std::env::temp_dir().join(format!("repub-test-{}-{}-{}.epub",
+                                                   std::process::id(),
+                                                   BUILD_COUNT.fetch_add(1, Ordering::SeqCst),
+                                                   name.replace(|c: char| !c.is_ascii_alphanumeric(), "_")));
+
+    let mut builder = RepubBuilder::from_path(&fixture(name));
+    builder
+        .titled("Test Book")
+        .creator("Tester")
+        .language("en")
+        .output(output);
+    configure(&mut builder);
+
+    let path = builder.build()?;
+    let bytes = std::fs::read(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(bytes)
+}
+
+/// ファイルを介さずにmarkdownを変換する
+pub fn build_markdown(name: &str, markdown: &str) -> Epub {
+    let output = std::env::temp_dir().join(format!("repub-test-{}-{}-{}.epub",
+                                                   std::process::id(),
+                                                   BUILD_COUNT.fetch_add(1, Ordering::SeqCst),
+                                                   name));
+
+    let mut builder = RepubBuilder::from_markdown(name, markdown).unwrap();
+    builder
+        .titled("Test Book")
+        .creator("Tester")
+        .language("en")
+        .output(output);
+
+    let path = builder.build().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    ZipArchive::new(Cursor::new(bytes)).unwrap()
+}
+
+/// epub内のファイルを文字列で読む
+pub fn read(epub: &mut Epub, name: &str) -> String {
+    let mut file = epub.by_name(name)
+        .unwrap_or_else(|_| panic!("{} is not in epub", name));
+    let mut content = String::new();
+    file.read_to_string(&mut content).unwrap();
+    content
+}
+
+/// epub内のファイルをバイト列のまま読む 画像など用
+pub fn read_bytes(epub: &mut Epub, name: &str) -> Vec<u8> {
+    let mut file = epub.by_name(name)
+        .unwrap_or_else(|_| panic!("{} is not in epub", name));
+    let mut content = Vec::new();
+    file.read_to_end(&mut content).unwrap();
+    content
+}
+
+/// epub内のファイル名一覧
+pub fn names(epub: &mut Epub) -> Vec<String> {
+    (0..epub.len()).map(|i| epub.by_index(i).unwrap().name().to_string()).collect()
+}
@@ -0,0 +1,1296 @@
+//! .epubの構造を確かめる
+
+mod common;
+
+use common::{build, build_bytes_with, build_markdown, build_with, fixture, names, read, read_bytes, try_build_with};
+use repub::{ChapterNumbering, Direction, EpubVersion, RepubBuilder, TextStats, TocPosition, TocSource, Viewport, WritingMode};
+use zip::CompressionMethod;
+
+#[test]
+fn mimetype_is_first_and_stored() {
+    let mut epub = build("single/single.md");
+
+    let mut mimetype = epub.by_index(0).unwrap();
+    assert_eq!(mimetype.name(), "mimetype");
+    assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+
+    let mut content = String::new();
+    std::io::Read::read_to_string(&mut mimetype, &mut content).unwrap();
+    assert_eq!(content, "application/epub+zip");
+}
+
+#[test]
+fn mimetype_local_header_has_no_extra_field() {
+    let bytes = build_bytes_with("single/single.md", |_| {}).unwrap();
+    let u16_at = |i: usize| u16::from(bytes[i]) | u16::from(bytes[i + 1]) << 8;
+
+    // 先頭はローカルファイルヘッダー
+    assert_eq!(&bytes[0..4], b"PK\x03\x04");
+    // 圧縮方式 0 = stored
+    assert_eq!(u16_at(8), 0);
+    // ファイル名の長さと拡張フィールドの長さ
+    assert_eq!(u16_at(26), "mimetype".len() as u16);
+    assert_eq!(u16_at(28), 0);
+    assert_eq!(&bytes[30..38], b"mimetype");
+    assert_eq!(&bytes[38..58], b"application/epub+zip");
+}
+
+#[test]
+fn mimetype_is_first_in_central_directory() {
+    let mut epub = build("chapters");
+    assert_eq!(names(&mut epub)[0], "mimetype");
+    assert_eq!(epub.by_index(0).unwrap().compression(), CompressionMethod::Stored);
+}
+
+#[test]
+fn container_points_to_package() {
+    let mut epub = build("single/single.md");
+    let container = read(&mut epub, "META-INF/container.xml");
+    assert!(container.contains("full-path=\"OEBPS/package.opf\""));
+}
+
+#[test]
+fn container_rootfile_is_written_package() {
+    for version in &[EpubVersion::V3, EpubVersion::V2] {
+        let mut epub = build_with("single/single.md", |builder| { builder.epub_version(*version); });
+        let container = read(&mut epub, "META-INF/container.xml");
+        let start = container.find("full-path=\"").unwrap() + "full-path=\"".len();
+        let full_path = &container[start..start + container[start..].find('"').unwrap()];
+
+        assert!(read(&mut epub, full_path).contains("<package "), "{} is not package document", full_path);
+    }
+}
+
+#[test]
+fn content_dir_can_be_renamed() {
+    let mut epub = build_with("single/single.md", |builder| { builder.content_dir("EPUB"); });
+    assert!(read(&mut epub, "META-INF/container.xml").contains("full-path=\"EPUB/package.opf\""));
+    assert!(names(&mut epub).iter().all(|name| name == "mimetype" || name.starts_with("META-INF/") || name.starts_with("EPUB/")));
+    assert!(read(&mut epub, "EPUB/single.xhtml").contains("styles/base.css"));
+
+    for content_dir in &["", "META-INF", "a/b", ".."] {
+        assert!(try_build_with("single/single.md", |builder| { builder.content_dir(content_dir); }).is_err(), "{:?}", content_dir);
+    }
+}
+
+#[test]
+fn package_has_metadata_and_items() {
+    let mut epub = build("chapters");
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    assert!(opf.contains("<dc:title>Test Book</dc:title>"));
+    assert!(opf.contains("<dc:language>en</dc:language>"));
+    assert!(opf.contains(">Tester</dc:creator>"));
+    assert!(opf.contains("href=\"01.xhtml\""));
+    assert!(opf.contains("href=\"02.xhtml\""));
+    assert!(opf.contains("properties=\"nav\""));
+
+    // 読む順は名前順
+    let first = opf.find("<itemref idref=\"book_0\"").unwrap();
+    let second = opf.find("<itemref idref=\"book_1\"").unwrap();
+    assert!(first < second);
+}
+
+#[test]
+fn navigation_lists_headings() {
+    let mut epub = build("chapters");
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+
+    assert!(nav.contains("epub:type=\"toc\""));
+    assert!(nav.contains(">Chapter One</a>"));
+    assert!(nav.contains(">Chapter Two</a>"));
+    assert!(nav.contains(">Part</a>"));
+}
+
+#[test]
+fn every_file_is_packed() {
+    let mut epub = build("chapters");
+    let names = names(&mut epub);
+
+    for name in &["META-INF/container.xml", "OEBPS/package.opf", "OEBPS/navigation.xhtml",
+        "OEBPS/toc.ncx", "OEBPS/01.xhtml", "OEBPS/02.xhtml", "OEBPS/styles/base.css"] {
+        assert!(names.iter().any(|n| n == name), "{} is missing in {:?}", name, names);
+    }
+}
+
+#[test]
+fn entry_names_are_relative() {
+    let mut epub = build("chapters");
+
+    for name in names(&mut epub) {
+        assert!(!name.starts_with('.') && !name.starts_with('/'), "{} is not relative", name);
+        assert!(!name.contains('\\'), "{} contains a backslash", name);
+    }
+}
+
+#[test]
+fn entry_names_have_no_dot_prefix_under_relative_tmp_dir() {
+    // 作業ディレクトリが"./..."でも、zip内の名前に"./"を残さない
+    let tmp_dir = std::path::PathBuf::from(".").join("target").join(format!("repub-test-relative-{}", std::process::id()));
+    std::fs::create_dir_all(&tmp_dir).unwrap();
+    let mut epub = build_with("chapters", |builder| { builder.tmp_dir(tmp_dir.clone()); });
+    std::fs::remove_dir_all(&tmp_dir).unwrap();
+
+    let names = names(&mut epub);
+    assert!(names.iter().any(|name| name == "META-INF/container.xml"), "{:?}", names);
+    for name in &names {
+        assert!(!name.starts_with("./") && !name.contains("/./"), "{} has a dot segment", name);
+    }
+}
+
+#[test]
+fn epub2_pages_are_xhtml11() {
+    let mut epub = build_with("footnotes/footnotes.md", |builder| {
+        builder.epub_version(EpubVersion::V2).cover(fixture("assets/cover.png")).title_page(true);
+    });
+    for name in &["OEBPS/footnotes.xhtml", "OEBPS/cover.xhtml", "OEBPS/title.xhtml"] {
+        let page = read(&mut epub, name);
+        assert!(page.contains("<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\""), "{} is not XHTML 1.1", name);
+        for html5 in &["epub:type", "<section", "<meta charset", "aria-hidden"] {
+            assert!(!page.contains(html5), "{} contains {}", name, html5);
+        }
+    }
+    // 脚注のidはEPUB3と同じくファイルごとに区別する
+    let page = read(&mut epub, "OEBPS/footnotes.xhtml");
+    assert!(page.contains("<div class=\"footnotes\">"));
+    assert!(page.contains("id=\"footnotes-fnref1\""));
+
+    let page = read(&mut build("footnotes/footnotes.md"), "OEBPS/footnotes.xhtml");
+    assert!(page.contains("<section class=\"footnotes\" epub:type=\"footnotes\">"));
+    assert!(page.contains("epub:type=\"noteref\""));
+}
+
+#[test]
+fn epub2_has_no_navigation() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.epub_version(EpubVersion::V2);
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    assert!(opf.contains("version=\"2.0\""));
+    assert!(!names(&mut epub).iter().any(|n| n == "OEBPS/navigation.xhtml"));
+}
+
+#[test]
+fn fonts_are_embedded() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.font(fixture("assets/Sample.otf"));
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    let css = read(&mut epub, "OEBPS/styles/fonts.css");
+
+    assert!(names(&mut epub).iter().any(|n| n == "OEBPS/fonts/Sample.otf"));
+    assert!(opf.contains("href=\"fonts/Sample.otf\" media-type=\"application/vnd.ms-opentype\""));
+    assert!(css.contains("font-family: \"Sample\""));
+}
+
+#[test]
+fn unsupported_font_is_rejected() {
+    let result = try_build_with("single/single.md", |builder| {
+        builder.font(fixture("assets/Sample.txt"));
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn single_stylesheet_becomes_custom_css() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.style(fixture("assets/first.css"));
+    });
+    assert_eq!(read(&mut epub, "OEBPS/styles/custom.css"), "body { color: black; }\n");
+}
+
+#[test]
+fn stylesheets_are_linked_in_order() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder
+            .style(fixture("assets/second.css"))
+            .style(fixture("assets/first.css"));
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    let xhtml = read(&mut epub, "OEBPS/single.xhtml");
+
+    assert_eq!(read(&mut epub, "OEBPS/styles/first.css"), "body { color: black; }\n");
+    assert!(opf.contains("href=\"styles/second.css\""));
+    assert!(opf.contains("href=\"styles/first.css\""));
+
+    let second = xhtml.find("href=\"styles/second.css\"").unwrap();
+    let first = xhtml.find("href=\"styles/first.css\"").unwrap();
+    assert!(second < first);
+}
+
+#[test]
+fn assets_referenced_from_css_are_copied() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.style(fixture("assets/theme.css"));
+    });
+    let css = read(&mut epub, "OEBPS/styles/custom.css");
+    // 同じ画像は1つだけ置く
+    assert_eq!(css.matches("url(\"cover.png\")").count(), 2);
+    assert!(css.contains("url(\"Sample.otf#sample\")"));
+    // データURIと外部のURLはそのまま
+    assert!(css.contains("url(data:image/gif;base64,R0lGODlhAQABAAAAACw=)"));
+    assert!(css.contains("url(https://example.com/quote.png)"));
+
+    let entries = names(&mut epub);
+    assert!(entries.iter().any(|name| name == "OEBPS/styles/cover.png"));
+    assert!(entries.iter().any(|name| name == "OEBPS/styles/Sample.otf"));
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert_eq!(opf.matches("href=\"styles/cover.png\" media-type=\"image/png\"").count(), 1);
+    assert!(opf.contains("href=\"styles/Sample.otf\""));
+}
+
+#[test]
+fn custom_css_is_linked_from_every_page() {
+    let mut epub = build_with("chapters", |builder| {
+        builder.style(fixture("assets/first.css"));
+    });
+
+    for name in &["OEBPS/01.xhtml", "OEBPS/02.xhtml", "OEBPS/navigation.xhtml"] {
+        // xhtmlはOEBPS直下にあるので、stylesからの相対path
+        let xhtml = read(&mut epub, name);
+        assert!(xhtml.contains("href=\"styles/custom.css\""), "{} does not link custom.css", name);
+    }
+}
+
+#[test]
+fn metadata_and_titles_are_escaped() {
+    let mut epub = build_with("escape/escape.md", |builder| {
+        builder
+            .titled("A & B <tag> \"quote\"")
+            .creator("Tom & Jerry")
+            .publisher("<Press>")
+            .rights("© 2019 Tom & Jerry");
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+    let ncx = read(&mut epub, "OEBPS/toc.ncx");
+
+    assert!(opf.contains("<dc:title>A &amp; B &lt;tag&gt; &quot;quote&quot;</dc:title>"));
+    assert!(opf.contains(">Tom &amp; Jerry</dc:creator>"));
+    assert!(opf.contains("<dc:publisher>&lt;Press&gt;</dc:publisher>"));
+    assert!(opf.contains("<dc:rights>© 2019 Tom &amp; Jerry</dc:rights>"));
+    assert!(!opf.contains("<tag>"));
+
+    assert!(nav.contains(">Fish &amp; Chips &lt;1&gt;</a>"));
+    assert!(ncx.contains("<text>Fish &amp; Chips &lt;1&gt;</text>"));
+}
+
+#[test]
+fn series_is_written_for_calibre_and_epub3() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.series("Cats & Dogs").series_index("2").unwrap();
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(opf.contains("<meta name=\"calibre:series\" content=\"Cats &amp; Dogs\" />"));
+    assert!(opf.contains("<meta name=\"calibre:series_index\" content=\"2\" />"));
+    assert!(opf.contains("<meta property=\"belongs-to-collection\" id=\"series\">Cats &amp; Dogs</meta>"));
+    assert!(opf.contains("<meta refines=\"#series\" property=\"collection-type\">series</meta>"));
+    assert!(opf.contains("<meta refines=\"#series\" property=\"group-position\">2</meta>"));
+
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.epub_version(EpubVersion::V2).series("Cats");
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(opf.contains("<meta name=\"calibre:series\" content=\"Cats\" />"));
+    assert!(!opf.contains("belongs-to-collection"));
+
+    let opf = read(&mut build("single/single.md"), "OEBPS/package.opf");
+    assert!(!opf.contains("series"));
+
+    assert!(RepubBuilder::from_path(&fixture("single/single.md")).series_index("second").is_err());
+}
+
+#[test]
+fn contributors_have_roles() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.contributor("Illustrator:ill").contributor("Helper");
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    assert!(opf.contains("<dc:contributor id=\"contributor_0\">Illustrator</dc:contributor>"));
+    assert!(opf.contains("<meta refines=\"#contributor_0\" property=\"role\" scheme=\"marc:relators\">ill</meta>"));
+    assert!(opf.contains("<meta refines=\"#contributor_1\" property=\"role\" scheme=\"marc:relators\">ctb</meta>"));
+    assert!(opf.contains("<dc:creator id=\"creator_0\">"));
+}
+
+#[test]
+fn subjects_are_listed() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.subject("Fiction").subject("  ").subject("Mystery");
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    assert!(opf.contains("<dc:subject>Fiction</dc:subject>"));
+    assert!(opf.contains("<dc:subject>Mystery</dc:subject>"));
+    assert_eq!(opf.matches("<dc:subject>").count(), 2);
+}
+
+#[test]
+fn isbn_is_written_with_scheme() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.isbn("978-4-87311-565-8").unwrap();
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(opf.contains("unique-identifier=\"BookId\""));
+    assert!(opf.contains("<dc:identifier id=\"BookId\">urn:isbn:9784873115658</dc:identifier>"));
+    assert!(opf.contains("property=\"identifier-type\" scheme=\"onix:codelist5\">15</meta>"));
+
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.isbn("9784873115658").unwrap().epub_version(EpubVersion::V2);
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(opf.contains("<dc:identifier id=\"BookId\" opf:scheme=\"ISBN\">9784873115658</dc:identifier>"));
+}
+
+#[test]
+fn invalid_isbn_is_rejected() {
+    let mut builder = repub::RepubBuilder::from_path(&fixture("single/single.md"));
+    assert!(builder.isbn("978-4-87311-565-9").is_err());
+    assert!(builder.isbn("12345").is_err());
+    assert!(builder.isbn("0-306-40615-2").is_ok());
+    assert!(builder.book_id("urn:isbn:978-4-87311-565-9").is_err());
+}
+
+#[test]
+fn isbn_book_id_is_normalized() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.book_id("urn:isbn:0-306-40615-2").unwrap();
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(opf.contains("<dc:identifier id=\"BookId\">urn:isbn:0306406152</dc:identifier>"));
+    // ハイフンを除いた10桁はISBN-10
+    assert!(opf.contains("property=\"identifier-type\" scheme=\"onix:codelist5\">02</meta>"));
+}
+
+#[test]
+fn bare_uuid_becomes_urn() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.book_id("123E4567-E89B-12D3-A456-426614174000").unwrap();
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(opf.contains(">urn:uuid:123e4567-e89b-12d3-a456-426614174000</dc:identifier>"));
+}
+
+#[test]
+fn default_id_is_uuid_urn() {
+    let mut epub = build("single/single.md");
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    let ncx = read(&mut epub, "OEBPS/toc.ncx");
+
+    let start = opf.find("<dc:identifier id=\"BookId\">urn:uuid:").unwrap() + "<dc:identifier id=\"BookId\">".len();
+    let id = &opf[start..start + "urn:uuid:".len() + 36];
+    assert!(ncx.contains(id), "toc.ncx does not use {}", id);
+}
+
+#[test]
+fn deterministic_id_is_stable() {
+    let identifier = || {
+        let mut epub = build_with("single/single.md", |builder| {
+            builder.deterministic_id(true);
+        });
+        let opf = read(&mut epub, "OEBPS/package.opf");
+        let start = opf.find("<dc:identifier").unwrap();
+        let end = opf[start..].find("</dc:identifier>").unwrap();
+        opf[start..start + end].to_string()
+    };
+
+    let first = identifier();
+    assert!(first.contains("urn:uuid:"));
+    assert_eq!(first, identifier());
+}
+
+#[test]
+fn deterministic_builds_are_identical() {
+    let build = || build_bytes_with("chapters", |builder| {
+        builder.deterministic(true);
+    }).unwrap();
+
+    let first = build();
+    assert!(first == build(), "two deterministic builds differ");
+}
+
+#[test]
+fn failed_build_leaves_no_epub() {
+    // 出力先の親がファイルなので書き込めない
+    let blocker = std::env::temp_dir().join(format!("repub-test-blocker-{}", std::process::id()));
+    std::fs::write(&blocker, "").unwrap();
+    let output = blocker.join("book.epub");
+
+    let result = try_build_with("single/single.md", |builder| {
+        builder.output(output.clone());
+    });
+    std::fs::remove_file(&blocker).unwrap();
+    assert!(result.is_err());
+    assert!(!output.exists());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn partly_written_epub_is_removed() {
+    // /dev/fullは開けるが書き込みは必ず失敗する(rootでも) 出力先はそこへのsymlinkにする
+    let dir = std::env::temp_dir().join(format!("repub-test-partial-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("book.epub");
+    std::os::unix::fs::symlink("/dev/full", &output).unwrap();
+
+    let result = try_build_with("single/single.md", |builder| {
+        builder.output(output.clone());
+    });
+    let left = output.symlink_metadata().is_ok();
+    // remove_dir_allはsymlinkの先をたどらない
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(result.is_err());
+    assert!(!left, "partly written epub is left");
+}
+
+#[test]
+fn saved_tmp_files_are_kept() {
+    let mut builder = repub::RepubBuilder::from_path(&fixture("single/single.md"));
+    builder
+        .titled("Test Book")
+        .language("en")
+        .output(std::env::temp_dir().join(format!("repub-test-saved-{}.epub", std::process::id())))
+        .save_tmp_files(true);
+    let epub = builder.build().unwrap();
+
+    let dir = builder.saved_tmp_dir().unwrap().to_path_buf();
+    assert!(dir.join("mimetype").is_file());
+    assert!(dir.join("OEBPS").join("package.opf").is_file());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_file(epub).unwrap();
+}
+
+#[test]
+fn output_dir_keeps_title_as_file_name() {
+    let dist = std::env::temp_dir().join(format!("repub-test-dist-{}", std::process::id()));
+    let mut builder = RepubBuilder::from_path(&fixture("single/single.md"));
+    builder
+        .titled("Test Book")
+        .language("en")
+        .output_dir(dist.join("nested"))
+        .tmp_dir(dist.join("tmp"))
+        .save_tmp_files(true);
+    let epub = builder.build().unwrap();
+
+    assert_eq!(epub, dist.join("nested").join("Test Book.epub"));
+    assert!(epub.is_file());
+    assert!(builder.saved_tmp_dir().unwrap().starts_with(dist.join("tmp")));
+
+    std::fs::remove_dir_all(dist).unwrap();
+}
+
+#[test]
+fn rebuilding_does_not_reuse_saved_tmp_files() {
+    let mut builder = repub::RepubBuilder::from_path(&fixture("single/single.md"));
+    builder
+        .titled("Test Book")
+        .language("en")
+        .output(std::env::temp_dir().join(format!("repub-test-rebuild-{}.epub", std::process::id())))
+        .save_tmp_files(true);
+    builder.build().unwrap();
+    let first = builder.saved_tmp_dir().unwrap().to_path_buf();
+    std::fs::write(first.join("OEBPS").join("styles").join("custom.css"), "/* edited */").unwrap();
+
+    let epub = builder.build().unwrap();
+    let second = builder.saved_tmp_dir().unwrap().to_path_buf();
+    assert_ne!(first, second);
+    assert_eq!(std::fs::read_to_string(first.join("OEBPS").join("styles").join("custom.css")).unwrap(), "/* edited */");
+
+    std::fs::remove_dir_all(first).unwrap();
+    std::fs::remove_dir_all(second).unwrap();
+    std::fs::remove_file(epub).unwrap();
+}
+
+#[test]
+fn heading_ids_are_namespaced_by_chapter() {
+    let mut epub = build("duplicate_headings");
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+    let first = read(&mut epub, "OEBPS/01.xhtml");
+
+    assert!(nav.contains("href=\"01.xhtml#01-header-introduction\""));
+    assert!(nav.contains("href=\"02.xhtml#02-header-introduction\""));
+    assert!(first.contains("id=\"01-header-introduction\""));
+    assert!(first.contains("href=\"#01-header-introduction\""));
+    assert!(!first.contains("\"header-introduction\""));
+}
+
+#[test]
+fn spine_position_moves_page_in_reading_order() {
+    let mut epub = build("spine");
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    // manifestはファイルの順のまま
+    let manifest_first = opf.find("href=\"01.xhtml\"").unwrap();
+    let manifest_colophon = opf.find("href=\"99.xhtml\"").unwrap();
+    assert!(manifest_first < manifest_colophon);
+
+    // 99.mdはbook_2 spineでは本文の先頭
+    let colophon = opf.find("<itemref idref=\"book_2\"").unwrap();
+    let first = opf.find("<itemref idref=\"book_0\"").unwrap();
+    assert!(colophon < first);
+}
+
+#[test]
+fn non_linear_pages_are_marked() {
+    let mut epub = build("spine");
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    // notes.mdはbook_3
+    assert!(opf.contains("<itemref idref=\"book_3\" linear=\"no\" />"));
+    assert!(opf.contains("<itemref idref=\"book_0\" />"));
+}
+
+#[test]
+fn cover_comes_before_navigation() {
+    let mut epub = build_with("chapters", |builder| {
+        builder.cover(fixture("assets/cover.png")).landmarks(true);
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    let spine = &opf[opf.find("<spine").unwrap()..];
+
+    assert!(spine.find("idref=\"cover\"").unwrap() < spine.find("idref=\"navigation\"").unwrap());
+    assert!(opf.contains("<meta name=\"cover\" content=\"cover_image\" />"));
+    assert!(opf.contains("properties=\"cover-image\""));
+    assert!(read(&mut epub, "OEBPS/navigation.xhtml").contains("<a epub:type=\"cover\" href=\"cover.xhtml\">"));
+}
+
+#[test]
+fn landmarks_point_to_toc_and_body() {
+    let mut epub = build_with("chapters", |builder| {
+        builder.landmarks(true);
+    });
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+
+    assert!(nav.contains("<nav epub:type=\"landmarks\""));
+    assert!(nav.contains("<a epub:type=\"toc\" href=\"navigation.xhtml\">"));
+    assert!(nav.contains("<a epub:type=\"bodymatter\" href=\"01.xhtml\">"));
+    assert!(!nav.contains("epub:type=\"cover\""));
+
+    let mut epub = build("chapters");
+    assert!(!read(&mut epub, "OEBPS/navigation.xhtml").contains("landmarks"));
+}
+
+#[test]
+fn markdown_without_file_becomes_one_chapter() {
+    let mut epub = build_markdown("notes", "# Notes\n\nfrom stdin\n");
+
+    assert!(read(&mut epub, "OEBPS/notes.xhtml").contains("from stdin"));
+    assert!(read(&mut epub, "OEBPS/navigation.xhtml").contains("Notes"));
+}
+
+#[test]
+fn publication_date_is_written_only_when_set() {
+    let mut epub = build_with("single/single.md", |builder| { builder.date("2019-05-01").unwrap(); });
+    assert!(read(&mut epub, "OEBPS/package.opf").contains("<dc:date>2019-05-01</dc:date>"));
+
+    let mut epub = build("single/single.md");
+    assert!(!read(&mut epub, "OEBPS/package.opf").contains("<dc:date>"));
+}
+
+#[test]
+fn invalid_date_is_rejected() {
+    let mut builder = RepubBuilder::from_path(&fixture("single/single.md"));
+    assert!(builder.date("2019-13-01").is_err());
+    assert!(builder.date("yesterday").is_err());
+    assert!(builder.date("2019-05-01T09:00:00+09:00").is_ok());
+}
+
+#[test]
+fn direction_follows_vertical_unless_set() {
+    let spine = |configure: fn(&mut RepubBuilder)| {
+        let mut epub = build_with("single/single.md", configure);
+        let opf = read(&mut epub, "OEBPS/package.opf");
+        opf[opf.find("<spine").unwrap()..].lines().next().unwrap().to_string()
+    };
+
+    assert_eq!(spine(|_| {}), "<spine toc=\"ncx\">");
+    assert_eq!(spine(|builder| { builder.vertical(true); }), "<spine toc=\"ncx\" page-progression-direction=\"rtl\">");
+    assert_eq!(spine(|builder| { builder.direction(Direction::Rtl); }), "<spine toc=\"ncx\" page-progression-direction=\"rtl\">");
+    assert_eq!(spine(|builder| { builder.vertical(true).direction(Direction::Ltr); }), "<spine toc=\"ncx\" page-progression-direction=\"ltr\">");
+    assert_eq!(spine(|builder| { builder.writing_mode(WritingMode::VerticalLr); }), "<spine toc=\"ncx\" page-progression-direction=\"ltr\">");
+}
+
+#[test]
+fn writing_mode_is_written_to_vertical_css() {
+    let mut epub = build_with("single/single.md", |builder| { builder.writing_mode(WritingMode::VerticalLr); });
+    let css = read(&mut epub, "OEBPS/styles/vertical.css");
+    assert!(css.contains("-epub-writing-mode: vertical-lr;"));
+    assert!(css.contains("    writing-mode: vertical-lr;"));
+    assert!(read(&mut epub, "OEBPS/single.xhtml").contains("styles/vertical.css"));
+
+    let mut epub = build("single/single.md");
+    assert!(!read(&mut epub, "OEBPS/single.xhtml").contains("styles/vertical.css"));
+}
+
+#[test]
+fn strikethrough_is_rendered() {
+    let mut epub = build_markdown("strike", "~~old~~\n");
+    assert!(read(&mut epub, "OEBPS/strike.xhtml").contains("<del>old</del>"));
+}
+
+#[test]
+fn highlights_are_marked() {
+    let mut epub = build_markdown("mark", "==marked== and `==code==`\n\n```\n==block==\n```\n\na == b == c\n");
+    let xhtml = read(&mut epub, "OEBPS/mark.xhtml");
+    assert!(xhtml.contains("<mark>marked</mark>"));
+    assert!(xhtml.contains("<code>==code==</code>"));
+    assert!(xhtml.contains("==block=="));
+    assert!(xhtml.contains("a == b == c"));
+}
+
+#[test]
+fn task_list_items_are_closed_checkboxes() {
+    let mut epub = build("tasklist/tasklist.md");
+    let xhtml = read(&mut epub, "OEBPS/tasklist.xhtml");
+
+    assert!(xhtml.contains("<li class=\"task-list-item\"><input type=\"checkbox\" disabled=\"\" checked=\"\" /> done</li>"));
+    assert!(xhtml.contains("<li class=\"task-list-item\"><input type=\"checkbox\" disabled=\"\" /> todo</li>"));
+    assert!(xhtml.contains("<li>plain</li>"));
+    assert_eq!(xhtml.matches("<li class=\"task-list-item\">\n<p><input").count(), 2);
+
+    // inputは空要素として閉じる
+    let inputs: Vec<&str> = xhtml.split("<input").skip(1).collect();
+    assert_eq!(inputs.len(), 4);
+    for input in inputs {
+        let end = input.find('>').unwrap();
+        assert!(input[..end].ends_with(" /"), "unclosed input: <input{}", &input[..=end]);
+    }
+}
+
+#[test]
+fn math_becomes_mathml() {
+    let mut epub = build_with("math/math.md", |builder| { builder.math(true); });
+    let xhtml = read(&mut epub, "OEBPS/math.xhtml");
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    assert!(xhtml.contains("display=\"inline\"><mi>a_1 + b_1</mi></math>"));
+    assert!(xhtml.contains("display=\"block\"><mi>\\sum_{i=1}^n i</mi></math>"));
+    assert!(!xhtml.contains("<p><math"));
+    assert!(xhtml.contains("prices $5 and $10."));
+    // 変換できない数式は元のまま
+    assert!(xhtml.contains("Broken $\\frac{1}{2$ stays."));
+    assert!(xhtml.contains("<code>$code$</code>"));
+    assert!(xhtml.contains("$$fenced$$"));
+    assert!(xhtml.contains("$escaped$"));
+    assert!(opf.contains("href=\"math.xhtml\" media-type=\"application/xhtml+xml\" properties=\"mathml\""));
+}
+
+#[test]
+fn math_is_left_alone_by_default() {
+    let mut epub = build("math/math.md");
+    assert!(!read(&mut epub, "OEBPS/math.xhtml").contains("<math"));
+    assert!(!read(&mut epub, "OEBPS/package.opf").contains("mathml"));
+}
+
+#[test]
+fn split_level_makes_one_page_per_heading() {
+    let mut epub = build_with("split/book.md", |builder| { builder.split_level(1); });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+
+    let spine: Vec<&str> = opf.lines().filter(|line| line.starts_with("<itemref")).collect();
+    assert_eq!(spine.len(), 4);
+    for href in &["prologue.xhtml", "prologue_2.xhtml", "prologue_3.xhtml"] {
+        assert!(opf.contains(&format!("href=\"{}\"", href)));
+    }
+    assert!(read(&mut epub, "OEBPS/prologue_2.xhtml").contains("# not a heading"));
+
+    // 目次はファイルをまたぐ
+    assert!(nav.contains("href=\"prologue.xhtml#prologue-header-prologue\">Prologue</a>"));
+    assert!(nav.contains("href=\"prologue_2.xhtml#prologue_2-header-middle\">Middle</a>"));
+    assert!(nav.contains("href=\"prologue_3.xhtml#prologue_3-header-intro\">Intro</a>"));
+
+    // ほかのページの見出しへのリンク
+    assert!(read(&mut epub, "OEBPS/prologue.xhtml").contains("href=\"prologue_3.xhtml#prologue_3-header-end\""));
+    assert!(read(&mut epub, "OEBPS/prologue_3.xhtml").contains("href=\"prologue_2.xhtml#prologue_2-header-middle\""));
+}
+
+#[test]
+fn front_matter_title_names_the_page() {
+    let mut epub = build("titled");
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(opf.contains("href=\"getting-started.xhtml\""));
+    // 英数字のないタイトルはmdファイル名
+    assert!(opf.contains("href=\"02.xhtml\""));
+    assert!(opf.contains("href=\"getting-started_2.xhtml\""));
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+    assert!(nav.contains("href=\"getting-started.xhtml#getting-started-header-start\">Getting Started!</a>"));
+    assert!(nav.contains(">はじめに</a>"));
+}
+
+#[test]
+fn smart_punctuation_skips_code() {
+    let mut epub = build_with("smart/smart.md", |builder| { builder.smart(true); });
+    let xhtml = read(&mut epub, "OEBPS/smart.xhtml");
+
+    assert!(xhtml.contains("“Quoted” – and — ‘single’."));
+    assert!(xhtml.contains("<code>&quot;raw&quot; -- x</code>"));
+    assert!(xhtml.contains("&quot;block&quot; --- y"));
+
+    let mut epub = build("smart/smart.md");
+    assert!(read(&mut epub, "OEBPS/smart.xhtml").contains("&quot;Quoted&quot; -- and ---"));
+}
+
+#[test]
+fn chapters_can_override_language() {
+    let mut epub = build_with("languages", |builder| { builder.language("ja").other_language("en"); });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    assert!(opf.contains("<dc:language>ja</dc:language>\n    <dc:language>en</dc:language>"));
+    assert!(read(&mut epub, "OEBPS/01_main.xhtml").contains("<html xml:lang=\"ja\" lang=\"ja\""));
+    assert!(read(&mut epub, "OEBPS/02_appendix.xhtml").contains("<html xml:lang=\"en\" lang=\"en\""));
+}
+
+#[test]
+fn dry_run_writes_nothing() {
+    let output = std::env::temp_dir().join(format!("repub-test-{}-dry-run.epub", std::process::id()));
+    let mut builder = RepubBuilder::from_path(&fixture("chapters"));
+    builder
+        .titled("Test Book")
+        .creator("Tester")
+        .language("en")
+        .output(output.clone())
+        .save_tmp_files(true)
+        .dry_run(true);
+
+    assert_eq!(builder.build().unwrap(), output);
+    assert!(!output.exists());
+    assert!(builder.saved_tmp_dir().is_none());
+
+    let plan = builder.plan().unwrap();
+    let spine = &plan[plan.find("spine:\n").unwrap()..plan.find("manifest:\n").unwrap()];
+    assert!(spine.contains("    01.xhtml\n"));
+    assert!(spine.find("01.xhtml").unwrap() < spine.find("02.xhtml").unwrap());
+    let toc = &plan[plan.find("toc:\n").unwrap()..];
+    assert!(toc.contains("    Chapter One -> 01.xhtml#01-header-chapter-one\n"));
+    assert!(toc.contains("        Part -> 02.xhtml#02-header-part\n"));
+}
+
+#[test]
+fn empty_directory_is_rejected() {
+    let err = try_build_with("empty", |_| {}).err().unwrap();
+    assert!(err.to_string().contains("no Markdown files found"));
+}
+
+#[test]
+fn page_without_headings_is_in_toc() {
+    let mut epub = build("no_heading/notes.md");
+    assert!(read(&mut epub, "OEBPS/navigation.xhtml").contains("<a href=\"notes.xhtml\">notes</a>"));
+    assert!(read(&mut epub, "OEBPS/toc.ncx").contains("<content src=\"notes.xhtml\" />"));
+}
+
+#[test]
+fn every_page_is_reachable_from_toc() {
+    let mut epub = build_with("reachable", |builder| { builder.toc_depth(3); });
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+
+    assert!(nav.contains("<a href=\"01_first-steps.xhtml\">first steps</a>"));
+    assert!(nav.contains(">Deep</a>"));
+    assert!(!nav.contains(">Deeper</a>"));
+}
+
+#[test]
+fn custom_template_wraps_pages() {
+    let mut epub = build_with("single/single.md", |builder| { builder.template(fixture("assets/template.xhtml")); });
+    let xhtml = read(&mut epub, "OEBPS/single.xhtml");
+
+    assert!(xhtml.contains("<meta name=\"viewport\" content=\"width=device-width\" />"));
+    assert!(xhtml.contains("<header>running header</header>"));
+    assert!(xhtml.contains("<html xml:lang=\"en\" lang=\"en\""));
+    assert!(!xhtml.contains("{body}"));
+}
+
+#[test]
+fn template_without_placeholders_is_rejected() {
+    let err = try_build_with("single/single.md", |builder| { builder.template(fixture("assets/broken_template.xhtml")); })
+        .err().unwrap();
+    assert!(err.to_string().contains("{title}"));
+}
+
+#[test]
+fn viewport_is_written_only_when_given() {
+    let mut epub = build("single/single.md");
+    assert!(!read(&mut epub, "OEBPS/single.xhtml").contains("name=\"viewport\""));
+
+    let mut epub = build_with("single/single.md", |builder| { builder.viewport("600x800".parse().unwrap()); });
+    assert!(read(&mut epub, "OEBPS/single.xhtml").contains("<meta name=\"viewport\" content=\"width=600, height=800\" />"));
+
+    let mut epub = build_with("single/single.md", |builder| { builder.viewport(Viewport::DeviceWidth); });
+    assert!(read(&mut epub, "OEBPS/single.xhtml").contains("<meta name=\"viewport\" content=\"width=device-width\" />"));
+
+    assert!("0x800".parse::<Viewport>().is_err());
+    assert!("wide".parse::<Viewport>().is_err());
+}
+
+#[test]
+fn fixed_layout_is_pre_paginated() {
+    let mut epub = build_with("chapters", |builder| {
+        builder.fixed_layout(true).viewport("600x800".parse().unwrap()).direction(Direction::Rtl);
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    assert!(opf.contains("<meta property=\"rendition:layout\">pre-paginated</meta>"));
+    assert!(opf.contains("<itemref idref=\"navigation\" properties=\"rendition:layout-reflowable\" />"));
+    assert!(opf.contains("<itemref idref=\"book_1\" properties=\"rendition:page-spread-left\" />"));
+    assert!(opf.contains("<itemref idref=\"book_2\" properties=\"rendition:page-spread-right\" />"));
+    assert!(read(&mut epub, "OEBPS/01.xhtml").contains("href=\"styles/fixed_layout.css\""));
+    assert!(read(&mut epub, "OEBPS/01.xhtml").contains("<meta name=\"viewport\" content=\"width=600, height=800\" />"));
+}
+
+#[test]
+fn fixed_layout_needs_fixed_viewport() {
+    assert!(try_build_with("chapters", |builder| { builder.fixed_layout(true); }).is_err());
+    assert!(try_build_with("chapters", |builder| { builder.fixed_layout(true).viewport(Viewport::DeviceWidth); }).is_err());
+    assert!(try_build_with("chapters", |builder| {
+        builder.fixed_layout(true).viewport("600x800".parse().unwrap()).epub_version(EpubVersion::V2);
+    }).is_err());
+}
+
+#[test]
+fn image_alt_text_is_kept() {
+    let mut epub = build("alt/alt.md");
+    let xhtml = read(&mut epub, "OEBPS/alt.xhtml");
+    assert!(xhtml.contains("alt=\"A cat\""));
+
+    let err = try_build_with("alt/alt.md", |builder| { builder.strict_a11y(true); }).err().unwrap();
+    assert!(err.to_string().contains("images without alt text"));
+}
+
+#[test]
+fn pages_have_semantic_types() {
+    let mut epub = build_with("sections", |builder| { builder.landmarks(true); });
+
+    let preface = read(&mut epub, "OEBPS/01_preface.xhtml");
+    assert!(preface.contains("<body epub:type=\"frontmatter\">"));
+    assert!(preface.contains("<section epub:type=\"preface\">"));
+    assert!(read(&mut epub, "OEBPS/02_beginning.xhtml").contains("<section epub:type=\"chapter\">"));
+    let appendix = read(&mut epub, "OEBPS/03_appendix.xhtml");
+    assert!(appendix.contains("<body epub:type=\"backmatter\">"));
+    assert!(appendix.contains("<section epub:type=\"appendix\">"));
+    assert!(read(&mut epub, "OEBPS/navigation.xhtml").contains("<a epub:type=\"bodymatter\" href=\"02_beginning.xhtml\">"));
+}
+
+#[test]
+fn chapter_content_is_wrapped_in_section() {
+    let mut epub = build("single/single.md");
+    let xhtml = read(&mut epub, "OEBPS/single.xhtml");
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+
+    assert!(xhtml.contains("<body epub:type=\"bodymatter\">\n        <section epub:type=\"chapter\">"));
+    let section = &xhtml[xhtml.find("<section").unwrap()..xhtml.rfind("</section>").unwrap()];
+    assert!(section.contains("<h1>") && section.contains("<h2>"));
+    // 見出しの入れ子は目次でもそのまま
+    let hello = nav.find(">Hello</a>").unwrap();
+    let nested = nav[hello..].find("<ol>").unwrap() + hello;
+    assert!(nav[nested..].contains(">Section</a>"));
+}
+
+#[test]
+fn invalid_languages_are_rejected() {
+    let err = try_build_with("single/single.md", |builder| { builder.language("jp"); }).err().unwrap();
+    assert!(err.to_string().contains("did you mean ja?"));
+    assert!(try_build_with("single/single.md", |builder| { builder.language("english"); }).is_err());
+    assert!(try_build_with("single/single.md", |builder| { builder.other_language("en_US"); }).is_err());
+
+    assert!(try_build_with("single/single.md", |builder| { builder.language("zh-Hant-TW"); }).is_ok());
+    assert!(try_build_with("single/single.md", |builder| { builder.language("english").validate_language(false); }).is_ok());
+}
+
+#[test]
+fn bare_urls_are_linked() {
+    let mut epub = build("autolink/autolink.md");
+    let xhtml = read(&mut epub, "OEBPS/autolink.xhtml");
+
+    assert!(xhtml.contains("<a href=\"https://example.com/search?q=rust&amp;lang=ja\">https://example.com/search?q=rust&amp;lang=ja</a>"));
+    assert!(xhtml.contains("<a href=\"mailto:author@example.com\">author@example.com</a>"));
+    assert!(xhtml.contains("<a href=\"http://www.example.org\">www.example.org</a>"));
+    assert!(xhtml.contains("<a href=\"https://example.com/?a=1&amp;b=2\">plain link</a>"));
+    assert!(xhtml.contains("<a href=\"https://example.com/?a=1&amp;b=2\">an escaped one</a>"));
+}
+
+#[test]
+fn media_types_can_be_overridden() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.cover(fixture("assets/cover.png")).media_type_override(".PNG = image/apng").unwrap();
+    });
+    assert!(read(&mut epub, "OEBPS/package.opf").contains("href=\"images/cover.png\" media-type=\"image/apng\""));
+
+    assert!(RepubBuilder::default().media_type_override("png").is_err());
+    assert!(RepubBuilder::default().media_type_override("png=apng").is_err());
+}
+
+#[test]
+fn binary_files_are_zipped_unchanged() {
+    let mut epub = build_with("single/single.md", |builder| {
+        builder.cover(fixture("assets/cover.png"));
+    });
+    assert_eq!(read_bytes(&mut epub, "OEBPS/images/cover.png"), std::fs::read(fixture("assets/cover.png")).unwrap());
+}
+
+#[test]
+fn image_named_like_cover_does_not_replace_it() {
+    let mut epub = build_with("cover_image/cover_image.md", |builder| {
+        builder.cover(fixture("assets/cover.png"));
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert_eq!(opf.matches("href=\"images/cover.png\"").count(), 1);
+    assert_eq!(opf.matches("href=\"images/cover_2.png\"").count(), 1);
+    assert!(read(&mut epub, "OEBPS/cover.xhtml").contains("images/cover.png"));
+    assert!(read(&mut epub, "OEBPS/cover_image.xhtml").contains("src=\"images/cover_2.png\""));
+}
+
+#[test]
+fn svg_images_are_checked() {
+    let mut epub = build("svg/svg.md");
+    assert!(read(&mut epub, "OEBPS/package.opf").contains("href=\"images/figure.svg\" media-type=\"image/svg+xml\""));
+    assert!(read(&mut epub, "OEBPS/svg.xhtml").contains("src=\"images/figure.svg\""));
+
+    let err = try_build_with("broken_svg/broken_svg.md", |_| {}).err().unwrap();
+    assert!(err.to_string().contains("is not well-formed SVG"));
+}
+
+#[test]
+fn markdown_extensions_are_configurable() {
+    let mut epub = build("extensions");
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(opf.contains("href=\"01.xhtml\"") && opf.contains("href=\"02.xhtml\""));
+    assert!(!opf.contains("href=\"03.xhtml\""));
+
+    let mut epub = build_with("extensions", |builder| { builder.extensions(&["txt"]); });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(!opf.contains("href=\"01.xhtml\""));
+    assert!(opf.contains("href=\"03.xhtml\""));
+
+    assert!(try_build_with("extensions/03.txt", |_| {}).is_err());
+    assert!(try_build_with("extensions/01.markdown", |_| {}).is_ok());
+}
+
+#[test]
+fn navigation_position_in_spine() {
+    let spine = |epub: &mut common::Epub| {
+        let opf = read(epub, "OEBPS/package.opf");
+        opf[opf.find("<spine").unwrap()..].to_string()
+    };
+
+    let mut epub = build_with("chapters", |builder| { builder.toc_first(false); });
+    let last = spine(&mut epub);
+    assert!(last.find("idref=\"navigation\"").unwrap() > last.find("idref=\"book_1\"").unwrap());
+
+    let mut epub = build_with("single/single.md", |builder| { builder.flatten_single_chapter(true); });
+    assert!(!spine(&mut epub).contains("idref=\"navigation\""));
+    assert!(read(&mut epub, "OEBPS/package.opf").contains("properties=\"nav\""));
+
+    let mut epub = build_with("chapters", |builder| { builder.toc_in_spine("none".parse().unwrap()); });
+    assert!(!spine(&mut epub).contains("idref=\"navigation\""));
+    assert!(read(&mut epub, "OEBPS/package.opf").contains("<item id=\"navigation\" href=\"navigation.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\" />"));
+    assert!("middle".parse::<TocPosition>().is_err());
+
+    let mut epub = build_with("chapters", |builder| { builder.flatten_single_chapter(true); });
+    assert!(spine(&mut epub).contains("<itemref idref=\"navigation\" />\n<itemref idref=\"book_0\" />"));
+}
+
+#[test]
+fn spine_idrefs_resolve_to_manifest() {
+    for version in &[EpubVersion::V3, EpubVersion::V2] {
+        let mut epub = build_with("chapters", |builder| {
+            builder.epub_version(*version).cover(fixture("assets/cover.png"));
+        });
+        let opf = read(&mut epub, "OEBPS/package.opf");
+        // propertiesはEPUB3のみ
+        assert_eq!(opf.contains("properties="), *version == EpubVersion::V3);
+        let (manifest, spine) = opf.split_at(opf.find("<spine").unwrap());
+
+        for idref in spine.split("idref=\"").skip(1).map(|rest| &rest[..rest.find('"').unwrap()]) {
+            assert!(manifest.contains(&format!("<item id=\"{}\"", idref)), "{} is not in manifest", idref);
+        }
+    }
+}
+
+#[test]
+fn every_file_is_in_manifest() {
+    let mut epub = build_with("chapters", |builder| {
+        builder
+            .cover(fixture("assets/cover.png"))
+            .font(fixture("assets/Sample.otf"))
+            .style(fixture("assets/first.css"))
+            .style(fixture("assets/second.css"))
+            .fixed_layout(true)
+            .viewport("600x800".parse().unwrap());
+    });
+    let opf = read(&mut epub, "OEBPS/package.opf");
+
+    for name in names(&mut epub) {
+        if name == "mimetype" || name.starts_with("META-INF/") || name == "OEBPS/package.opf" || name.ends_with('/') {
+            continue;
+        }
+        let href = name.trim_start_matches("OEBPS/");
+        assert!(opf.contains(&format!("href=\"{}\"", href)), "{} is not in manifest", href);
+    }
+}
+
+#[test]
+fn raw_chapters_are_passed_through() {
+    let mut epub = build_with("chapters", |builder| {
+        builder.append_chapter(fixture("assets/copyright.xhtml"), Some(1));
+    });
+    let original = std::fs::read_to_string(fixture("assets/copyright.xhtml")).unwrap();
+    assert_eq!(read(&mut epub, "OEBPS/copyright.xhtml"), original);
+
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    let item = &opf[..opf.find("href=\"copyright.xhtml\"").unwrap()];
+    let id = &item[item.rfind("id=\"").unwrap() + 4..];
+    let id = &id[..id.find('"').unwrap()];
+    let spine = &opf[opf.find("<spine").unwrap()..];
+    assert!(spine.contains(&format!("<itemref idref=\"navigation\" />\n<itemref idref=\"{}\" />", id)));
+
+    assert!(read(&mut epub, "OEBPS/navigation.xhtml").contains("copyright.xhtml#copyright"));
+
+    let err = try_build_with("chapters", |builder| {
+        builder.append_chapter(fixture("assets/broken.xhtml"), None);
+    }).err().unwrap();
+    assert!(err.to_string().contains("is not well-formed XHTML"));
+}
+
+#[test]
+fn title_page_follows_cover() {
+    let mut epub = build_with("chapters", |builder| {
+        builder
+            .titled("Tom & Jerry")
+            .cover(fixture("assets/cover.png"))
+            .title_page(true)
+            .landmarks(true);
+        builder.date("2019-04-01").unwrap();
+    });
+    let page = read(&mut epub, "OEBPS/title.xhtml");
+    assert!(page.contains("<h1 class=\"title\">Tom &amp; Jerry</h1>"));
+    assert!(page.contains("<p class=\"date\">2019-04-01</p>"));
+    assert!(page.contains("href=\"styles/title_page.css\""));
+
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    assert!(opf.contains("<item id=\"title_page_css\" href=\"styles/title_page.css\" media-type=\"text/css\""));
+    let spine = &opf[opf.find("<spine").unwrap()..];
+    assert!(spine.contains("<itemref idref=\"cover\" />\n<itemref idref=\"titlepage\" />\n<itemref idref=\"navigation\" />"));
+
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+    assert!(nav.contains("<a epub:type=\"titlepage\" href=\"title.xhtml\">"));
+
+    let mut epub = build("chapters");
+    assert!(!names(&mut epub).iter().any(|name| name == "OEBPS/title.xhtml"));
+
+    // タイトルの中の{creators}や{date}は置き換えない
+    let mut epub = build_with("chapters", |builder| {
+        builder.titled("Notes on {creators} and {date}").title_page(true);
+        builder.date("2019-04-01").unwrap();
+    });
+    let page = read(&mut epub, "OEBPS/title.xhtml");
+    assert!(page.contains("<title>Notes on {creators} and {date}</title>"));
+    assert!(page.contains("<h1 class=\"title\">Notes on {creators} and {date}</h1>"));
+    assert_eq!(page.matches("<p class=\"creator\">").count(), 1);
+    assert_eq!(page.matches("<p class=\"date\">").count(), 1);
+}
+
+#[test]
+fn text_stats_count_markdown_body() {
+    let markdown = "---\ntitle: Stats\n---\n# Hello, world\n\n**吾輩は猫**である。\n\n- [link](http://example.com)\n";
+    let mut builder = RepubBuilder::from_markdown("stats", markdown).unwrap();
+    builder
+        .titled("Test Book")
+        .language("en")
+        .output(std::env::temp_dir().join(format!("repub-test-stats-{}.epub", std::process::id())))
+        .stats(true);
+    let epub = builder.build().unwrap();
+    std::fs::remove_file(epub).unwrap();
+
+    // 記号は数えない 英語はHello, world, link, http, example, comの6語28文字、日本語は7文字
+    assert_eq!(builder.text_stats(), Some(TextStats { words: 13, characters: 35 }));
+}
+
+#[test]
+fn includes_are_spliced_before_conversion() {
+    let mut epub = build("include");
+    let page = read(&mut epub, "OEBPS/01_chapter.xhtml");
+    assert!(page.contains("<p>This is a disclaimer.</p>"));
+    assert!(page.contains("<p>A <em>term</em> is defined here.</p>"));
+    assert!(!page.contains("ignored"));
+    // コードブロックの中はそのまま
+    assert!(page.contains("{% include &quot;parts/_disclaimer.md&quot; %}"));
+    // 部品は章にならない
+    assert!(!names(&mut epub).iter().any(|name| name.contains("disclaimer")));
+
+    let err = try_build_with("include_cycle", |_| {}).err().unwrap();
+    assert!(err.to_string().contains("recursively"));
+}
+
+#[test]
+fn raw_html_is_kept_only_when_allowed() {
+    let mut epub = build("raw_html/raw_html.md");
+    let page = read(&mut epub, "OEBPS/raw_html.xhtml");
+    assert!(!page.contains("<div class=\"note\">"));
+
+    let mut epub = build_with("raw_html/raw_html.md", |builder| { builder.allow_html(true); });
+    let page = read(&mut epub, "OEBPS/raw_html.xhtml");
+    assert!(page.contains("<div class=\"note\">"));
+    assert!(page.contains("<mark>marked</mark>"));
+    // インラインのSVGもmanifestで示す
+    assert!(read(&mut epub, "OEBPS/package.opf").contains("href=\"raw_html.xhtml\" media-type=\"application/xhtml+xml\" properties=\"svg\""));
+
+    let err = try_build_with("broken_html/broken_html.md", |builder| { builder.allow_html(true); }).err().unwrap();
+    assert!(err.to_string().contains("has malformed HTML"));
+}
+
+#[test]
+fn heading_with_inline_markup_is_one_toc_entry() {
+    let mut epub = build("inline_heading/inline_heading.md");
+    assert!(read(&mut epub, "OEBPS/navigation.xhtml").contains(">The bold code link</a>"));
+    assert!(read(&mut epub, "OEBPS/toc.ncx").contains("<text>The bold code link</text>"));
+}
+
+#[test]
+fn chapters_are_numbered_in_toc() {
+    let mut epub = build_with("chapters", |builder| { builder.number_chapters(ChapterNumbering::Decimal); });
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+    assert!(nav.contains(">1. Chapter One</a>"));
+    assert!(nav.contains(">2. Chapter Two</a>"));
+    assert!(nav.contains(">2.1 Part</a>"));
+    assert!(read(&mut epub, "OEBPS/toc.ncx").contains("<text>2.1 Part</text>"));
+
+    let mut epub = build_with("chapters", |builder| { builder.number_chapters(ChapterNumbering::Roman); });
+    assert!(read(&mut epub, "OEBPS/navigation.xhtml").contains(">II. Chapter Two</a>"));
+
+    let mut epub = build_with("chapters", |builder| { builder.number_chapters(ChapterNumbering::Cjk); });
+    assert!(read(&mut epub, "OEBPS/navigation.xhtml").contains(">第二章 Chapter Two</a>"));
+
+    let nav = read(&mut build("chapters"), "OEBPS/navigation.xhtml");
+    assert!(nav.contains(">Chapter One</a>"));
+}
+
+#[test]
+fn toc_from_files_lists_one_entry_per_chapter() {
+    let mut epub = build_with("chapters", |builder| { builder.toc_from(TocSource::Files); });
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+    assert!(nav.contains("<a href=\"02.xhtml\">02</a>"));
+    assert!(!nav.contains("Part"));
+    assert!(!read(&mut epub, "OEBPS/toc.ncx").contains("Part"));
+
+    // 見出しでなくファイル名から
+    let nav = read(&mut build_with("sections", |builder| { builder.toc_from(TocSource::Files); }), "OEBPS/navigation.xhtml");
+    assert!(nav.contains("<a href=\"02_beginning.xhtml\">beginning</a>"));
+    assert!(!nav.contains("Beginning"));
+}
+
+#[test]
+fn comrak_options_toggle_extensions() {
+    let page = read(&mut build("comrak"), "OEBPS/comrak.xhtml");
+    assert!(page.contains("<table>"));
+    assert!(page.contains("<del>old</del>"));
+    assert!(!page.contains("<sup>"));
+
+    let mut epub = build_with("comrak", |builder| {
+        builder
+            .comrak_option("tables=false").unwrap()
+            .comrak_option("strikethrough = false").unwrap()
+            .comrak_option("superscript").unwrap();
+    });
+    let page = read(&mut epub, "OEBPS/comrak.xhtml");
+    assert!(!page.contains("<table>"));
+    assert!(page.contains("~~old~~"));
+    assert!(page.contains("e<sup>x</sup>"));
+
+    // 使えるkeyを示す
+    let err = RepubBuilder::default().comrak_option("emoji=true").err().unwrap();
+    assert!(err.to_string().contains("tables, strikethrough"));
+    assert!(RepubBuilder::default().comrak_option("tables=yes").is_err());
+}
+
+#[test]
+fn merged_chapters_share_one_page() {
+    let mut epub = build_with("chapters", |builder| { builder.merge(true); });
+    let entries = names(&mut epub);
+    assert!(entries.iter().any(|name| name == "OEBPS/content.xhtml"));
+    assert!(!entries.iter().any(|name| name == "OEBPS/01.xhtml" || name == "OEBPS/02.xhtml"));
+
+    let page = read(&mut epub, "OEBPS/content.xhtml");
+    let one = page.find("<div class=\"chapter\" id=\"01\">").unwrap();
+    let two = page.find("<div class=\"chapter\" id=\"02\">").unwrap();
+    assert!(one < two);
+    assert!(page.contains("id=\"02-header-part\""));
+
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    let spine = &opf[opf.find("<spine").unwrap()..];
+    assert_eq!(spine.matches("<itemref").count(), 2);
+
+    let nav = read(&mut epub, "OEBPS/navigation.xhtml");
+    assert!(nav.contains("href=\"content.xhtml#01-header-chapter-one\""));
+    assert!(nav.contains("href=\"content.xhtml#02-header-part\""));
+
+    assert!(try_build_with("chapters", |builder| { builder.merge(true).split_level(2); }).is_err());
+}
+
+#[test]
+fn chapters_can_be_added_in_memory() {
+    let output = std::env::temp_dir().join(format!("repub-test-in-memory-{}.epub", std::process::id()));
+    let mut builder = RepubBuilder::default();
+    builder
+        .add_chapter("intro", "# Intro\n\nHello.")
+        .add_chapter("body", "# Body\n\nText.")
+        .add_chapter("intro", "# Intro again")
+        .titled("Test Book")
+        .language("en")
+        .output(output.clone());
+    let path = builder.build().unwrap();
+    assert_eq!(path, output);
+
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let mut epub = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+    let opf = read(&mut epub, "OEBPS/package.opf");
+    let intro = opf.find("href=\"intro.xhtml\"").unwrap();
+    let body = opf.find("href=\"body.xhtml\"").unwrap();
+    let again = opf.find("href=\"intro_2.xhtml\"").unwrap();
+    assert!(intro < body && body < again);
+    assert!(read(&mut epub, "OEBPS/body.xhtml").contains("<p>Text.</p>"));
+}
+
+#[test]
+fn failing_files_are_named_or_skipped() {
+    let err = try_build_with("partly_broken", |_| {}).err().unwrap().to_string();
+    assert!(err.contains("failed to convert") && err.contains("02_bad.md"), "{}", err);
+
+    let output = std::env::temp_dir().join(format!("repub-test-continue-{}.epub", std::process::id()));
+    let mut builder = RepubBuilder::from_path(&fixture("partly_broken"));
+    builder
+        .titled("Test Book")
+        .language("en")
+        .output(output.clone())
+        .continue_on_error(true);
+    builder.build().unwrap();
+    let failed: Vec<_> = builder.failed_files().iter().map(|(path, _)| path.file_name().unwrap().to_owned()).collect();
+    assert_eq!(failed, vec!["02_bad.md"]);
+
+    let bytes = std::fs::read(&output).unwrap();
+    std::fs::remove_file(&output).unwrap();
+    let mut epub = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+    let entries = names(&mut epub);
+    assert!(entries.iter().any(|name| name == "OEBPS/01_good.xhtml"));
+    assert!(entries.iter().any(|name| name == "OEBPS/03_good.xhtml"));
+    assert!(!entries.iter().any(|name| name == "OEBPS/02_bad.xhtml"));
+}
+
+#[test]
+fn non_utf8_markdown_is_transcoded() {
+    let mut epub = build("encoding");
+    assert!(read(&mut epub, "OEBPS/01_sjis.xhtml").contains("<p>名前はまだ無い。</p>"));
+    assert!(read(&mut epub, "OEBPS/02_eucjp.xhtml").contains("親譲りの無鉄砲"));
+    assert!(read(&mut epub, "OEBPS/03_latin1.xhtml").contains("<p>Crème brûlée.</p>"));
+
+    let mut epub = build_with("encoding/01_sjis.md", |builder| { builder.encoding("shift_jis").unwrap(); });
+    assert!(read(&mut epub, "OEBPS/01_sjis.xhtml").contains("吾輩は猫である"));
+
+    let err = try_build_with("encoding/01_sjis.md", |builder| { builder.encoding("utf-8").unwrap(); }).err().unwrap();
+    assert!(err.to_string().contains("is not valid UTF-8 text"));
+    let err = try_build_with("partly_broken/02_bad.md", |_| {}).err().unwrap();
+    assert!(err.to_string().contains("could not detect the encoding"));
+    assert!(RepubBuilder::from_path(&fixture("encoding")).encoding("klingon").is_err());
+}